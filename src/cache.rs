@@ -0,0 +1,343 @@
+use crate::json::Json;
+use crate::payload::{Block, Payload, Segment};
+use crate::recipe::{Part, Recipe};
+use num::BigUint;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/* A directory of pre-generated payloads.
+ *
+ * Each payload is written to <hash>.bin and described in manifest.json. Entries are named, and the
+ * hash covers the recipe as well as the contents of its -f files, so editing either one causes the
+ * entry to be rebuilt the next time it's asked for. So does the version of ied the entry was built
+ * with, since the same recipe can come out differently from one version to the next. */
+pub struct Cache {
+    dir: PathBuf,
+    version: String,
+    pub entries: Vec<Entry>,
+}
+
+pub struct Entry {
+    pub name: String,
+    pub recipe: String,
+    pub hash: String,
+    pub file: String,
+    pub encodings: Vec<String>,
+    pub compressed_size: u64,
+    pub final_size: BigUint,
+    pub crc32: [u8; 4],
+    pub adler32: [u8; 4],
+    pub created: u64,
+}
+
+const MANIFEST: &str = "manifest.json";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/* 64 bit FNV-1a. This only has to notice changes, and unlike std's hasher its output is stable
+ * across compiler versions. */
+fn fnv1a(state: u64, data: &[u8]) -> u64 {
+    let mut h = state;
+    for byte in data {
+        h ^= *byte as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    return h;
+}
+
+fn recipe_hash(version: &str, text: &str, recipe: &Recipe) -> String {
+    let mut h = fnv1a(0xcbf29ce484222325, &(version.len() as u64).to_le_bytes());
+    h = fnv1a(h, version.as_bytes());
+    h = fnv1a(h, text.as_bytes());
+    for part in recipe.parts.iter() {
+        if let Part::File(path) = part {
+            let contents = std::fs::read(path).expect("Failed to read file");
+            h = fnv1a(h, &(contents.len() as u64).to_le_bytes());
+            h = fnv1a(h, &contents);
+        }
+    }
+    return format!("{:016x}", h);
+}
+
+fn hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+fn unhex4(s: &str) -> Option<[u8; 4]> {
+    if s.len() != 8 {
+        return Option::None;
+    }
+    let n = u32::from_str_radix(s, 16).ok()?;
+    return Option::Some(n.to_be_bytes());
+}
+
+/* Splits a recipe line into arguments. Encodings are comma separated without spaces here, e.g.
+ * "gzip,gzip 1048576 -f head.html -l a -f tail.html". */
+pub fn recipe_args(text: &str) -> Vec<String> {
+    return text.split_whitespace().map(|s| s.to_string()).collect();
+}
+
+impl Entry {
+    fn to_json(&self) -> Json {
+        return Json::Object(vec![
+            ("name".to_string(), Json::String(self.name.clone())),
+            ("recipe".to_string(), Json::String(self.recipe.clone())),
+            ("hash".to_string(), Json::String(self.hash.clone())),
+            ("file".to_string(), Json::String(self.file.clone())),
+            ("encodings".to_string(), Json::Array(self.encodings.iter()
+                    .map(|e| Json::String(e.clone())).collect())),
+            ("compressed_size".to_string(), Json::Number(self.compressed_size.to_string())),
+            ("final_size".to_string(), Json::Number(self.final_size.to_string())),
+            ("crc32".to_string(), Json::String(hex(&self.crc32))),
+            ("adler32".to_string(), Json::String(hex(&self.adler32))),
+            ("created".to_string(), Json::Number(self.created.to_string())),
+        ]);
+    }
+
+    fn from_json(value: &Json) -> Option<Entry> {
+        let string = |key: &str| value.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+        let number = |key: &str| value.get(key).and_then(|v| v.as_number());
+
+        return Option::Some(Entry {
+            name: string("name")?,
+            recipe: string("recipe")?,
+            hash: string("hash")?,
+            file: string("file")?,
+            encodings: value.get("encodings")?.as_array()?.iter()
+                .map(|e| e.as_str().map(|s| s.to_string()))
+                .collect::<Option<Vec<String>>>()?,
+            compressed_size: number("compressed_size")?.parse().ok()?,
+            final_size: BigUint::from_str(number("final_size")?).ok()?,
+            crc32: unhex4(value.get("crc32")?.as_str()?)?,
+            adler32: unhex4(value.get("adler32")?.as_str()?)?,
+            created: number("created")?.parse().ok()?,
+        });
+    }
+}
+
+impl Cache {
+    /* Opens a cache directory, creating it if needed. A missing or unreadable manifest is treated
+     * as an empty cache, and an entry in it that can't be read as a missing one, so either way the
+     * payloads are built again when they're asked for. */
+    pub fn open(dir: &str) -> Cache {
+        let dir = PathBuf::from(dir);
+        std::fs::create_dir_all(&dir).expect("Failed to create cache directory");
+
+        let mut entries = Vec::<Entry>::new();
+        let manifest = std::fs::read_to_string(dir.join(MANIFEST)).ok().and_then(|t| Json::parse(&t));
+        if let Option::Some(manifest) = manifest {
+            for value in manifest.get("entries").and_then(|e| e.as_array()).unwrap_or(&[]) {
+                if let Option::Some(entry) = Entry::from_json(value) {
+                    entries.push(entry);
+                }
+            }
+        }
+
+        return Cache {
+            dir: dir,
+            version: VERSION.to_string(),
+            entries: entries,
+        };
+    }
+
+    pub fn save(&self) {
+        let manifest = Json::Object(vec![
+            ("entries".to_string(), Json::Array(self.entries.iter().map(|e| e.to_json()).collect())),
+        ]);
+        let tmp = self.dir.join(format!("{}.tmp", MANIFEST));
+        std::fs::write(&tmp, manifest.to_string() + "\n").expect("Failed to write cache manifest");
+        std::fs::rename(&tmp, self.dir.join(MANIFEST)).expect("Failed to write cache manifest");
+    }
+
+    pub fn path(&self, entry: &Entry) -> PathBuf {
+        return self.dir.join(&entry.file);
+    }
+
+    /* Returns the entry for name, building it first if it's missing or its recipe has changed. */
+    pub fn get(&mut self, name: &str, text: &str) -> &Entry {
+        let recipe = Recipe::parse(&recipe_args(text));
+        let hash = recipe_hash(&self.version, text, &recipe);
+
+        let idx = self.entries.iter().position(|e| e.name == name);
+        if let Option::Some(i) = idx {
+            let entry = &self.entries[i];
+            if entry.hash == hash && self.path(entry).is_file() {
+                return &self.entries[i];
+            }
+        }
+
+        let payload = recipe.build();
+        let file = format!("{}.bin", hash);
+        let tmp = self.dir.join(format!("{}.tmp", file));
+        let mut output = std::io::BufWriter::new(
+                std::fs::File::create(&tmp).expect("Failed to create cache file"));
+        let written = payload.write(&mut output).expect("Failed to write cache file").bytes;
+        drop(output);
+        std::fs::rename(&tmp, self.dir.join(&file)).expect("Failed to write cache file");
+
+        let entry = Entry {
+            name: name.to_string(),
+            recipe: text.to_string(),
+            hash: hash,
+            file: file,
+            encodings: recipe.encodings.clone(),
+            compressed_size: written as u64,
+            final_size: payload.final_size(),
+            crc32: payload.crc32(),
+            adler32: payload.adler32(),
+            created: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        };
+
+        let i = match idx {
+            Option::Some(i) => {
+                let old = std::mem::replace(&mut self.entries[i], entry);
+                if old.file != self.entries[i].file
+                        && self.entries.iter().all(|e| e.file != old.file) {
+                    let _ = std::fs::remove_file(self.path(&old));
+                }
+                i
+            }
+            Option::None => {
+                self.entries.push(entry);
+                self.entries.len() - 1
+            }
+        };
+        self.save();
+        return &self.entries[i];
+    }
+
+    /* Reads the cached payload for name, building it first if needed. The payload is the encoded
+     * bytes as they're sent, so it's returned along with the size they decompress to. */
+    pub fn load(&mut self, name: &str, text: &str) -> (Payload, BigUint) {
+        let entry = self.get(name, text);
+        let (file, final_size) = (entry.file.clone(), entry.final_size.clone());
+        let data = std::fs::read(self.dir.join(file)).expect("Failed to read cache file");
+        let payload = Payload::new(Box::new([Segment::Block(Block::new(data.into_boxed_slice()))]));
+        return (payload, final_size);
+    }
+}
+
+/* Reads a recipes file: one "[name] [content encoding] [size] [payload]..." recipe per line. Blank
+ * lines and lines starting with # are skipped. */
+pub fn read_recipes(path: &str) -> Vec<(String, String)> {
+    let text = std::fs::read_to_string(path).expect("Failed to read recipes");
+    let mut ret = Vec::<(String, String)>::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, recipe) = line.split_once(char::is_whitespace)
+            .unwrap_or_else(|| panic!("Recipe {} is empty", line));
+        ret.push((name.to_string(), recipe.trim().to_string()));
+    }
+    return ret;
+}
+
+/* ied cache build [directory] [recipes] */
+pub fn main(args: &[String]) {
+    if args.len() != 3 || args[0] != "build" {
+        println!("Usage: ied cache build [directory] [recipes]");
+        return;
+    }
+
+    let mut cache = Cache::open(&args[1]);
+    for (name, recipe) in read_recipes(&args[2]) {
+        let entry = cache.get(&name, &recipe);
+        println!("{} {} {} bytes -> {} bytes", entry.name, entry.file, entry.compressed_size,
+                entry.final_size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rebuild() {
+        let dir = std::env::temp_dir().join(format!("ied-cache-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let dir_str = dir.to_str().unwrap();
+
+        let mut cache = Cache::open(dir_str);
+        let first = cache.get("a", "gzip 1000 -l a").hash.clone();
+        let created = cache.entries[0].created;
+        drop(cache);
+
+        /* unchanged recipes are loaded from the manifest and not rebuilt */
+        let mut cache = Cache::open(dir_str);
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.entries[0].final_size, BigUint::from(1000u32 * 1032 + 1291));
+        assert_eq!(cache.get("a", "gzip 1000 -l a").hash, first);
+        assert_eq!(cache.entries[0].created, created);
+
+        let data = std::fs::read(cache.path(&cache.entries[0])).unwrap();
+        assert_eq!(data.len() as u64, cache.entries[0].compressed_size);
+        assert_eq!(&data[..2], &[0x1f, 0x8b]);
+
+        /* changed recipes are */
+        let second = cache.get("a", "gzip 2000 -l a").hash.clone();
+        assert_ne!(first, second);
+        assert_eq!(cache.entries.len(), 1);
+        assert!(!dir.join(format!("{}.bin", first)).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = std::env::temp_dir().join(format!("ied-cache-load-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let dir_str = dir.to_str().unwrap();
+
+        /* the payload as it was built, without building it again */
+        let mut cache = Cache::open(dir_str);
+        let (payload, final_size) = cache.load("a", "gzip 1000 -l a");
+        let mut expected = Vec::<u8>::new();
+        Recipe::parse(&recipe_args("gzip 1000 -l a")).build().write(&mut expected).unwrap();
+        let mut data = Vec::<u8>::new();
+        payload.write(&mut data).unwrap();
+        assert_eq!(data, expected);
+        assert_eq!(final_size, BigUint::from(1000u32 * 1032 + 1291));
+        drop(cache);
+
+        /* a manifest that isn't JSON, or has entries that don't make sense, is started over */
+        for manifest in ["{\"entries\": [", "{\"entries\": [{\"name\": \"a\"}]}"] {
+            std::fs::write(dir.join(MANIFEST), manifest).unwrap();
+            let mut cache = Cache::open(dir_str);
+            assert!(cache.entries.is_empty());
+            assert_eq!(cache.load("a", "gzip 1000 -l a").0.size(), BigUint::from(expected.len()));
+            assert_eq!(cache.entries.len(), 1);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_version() {
+        let dir = std::env::temp_dir().join(format!("ied-cache-version-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let dir_str = dir.to_str().unwrap();
+
+        /* an entry left behind by some other version of ied */
+        let mut cache = Cache::open(dir_str);
+        cache.version = "0.0.0".to_string();
+        let old = cache.get("a", "gzip 1000 -l a").hash.clone();
+        drop(cache);
+
+        /* is rebuilt, even though its recipe is the same */
+        let mut cache = Cache::open(dir_str);
+        let new = cache.get("a", "gzip 1000 -l a").hash.clone();
+        assert_ne!(old, new);
+        assert_eq!(cache.entries.len(), 1);
+        assert!(!dir.join(format!("{}.bin", old)).exists());
+        assert!(dir.join(format!("{}.bin", new)).exists());
+
+        /* and then kept */
+        drop(cache);
+        let mut cache = Cache::open(dir_str);
+        assert_eq!(cache.get("a", "gzip 1000 -l a").hash, new);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,89 @@
+use crate::recipe::Recipe;
+use num::BigUint;
+
+/* ied checksum: the sizes and checksums of every layer of a payload, without writing any of it.
+ *
+ * Layer 0 is the decompressed content and layer n is the n-th encoding applied to it, so the last
+ * layer is what would be sent. */
+pub struct Layer {
+    pub encoding: String,
+    pub size: BigUint,
+    pub crc32: [u8; 4],
+    pub adler32: [u8; 4],
+}
+
+pub fn layers(recipe: &Recipe) -> Vec<Layer> {
+    let payload = recipe.build();
+
+    let mut ret = Vec::<Layer>::new();
+    let mut layer = Option::Some(&payload);
+    while let Option::Some(l) = layer {
+        ret.push(Layer {
+            encoding: String::new(),
+            size: l.size(),
+            crc32: l.crc32(),
+            adler32: l.adler32(),
+        });
+        layer = l.child();
+    }
+    ret.reverse();
+
+    ret[0].encoding = "identity".to_string();
+    for (l, encoding) in ret[1..].iter_mut().zip(recipe.layer_encodings()) {
+        l.encoding = encoding.clone();
+    }
+    return ret;
+}
+
+fn hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+pub fn main(args: &[String]) {
+    if args.len() < 2 {
+        println!("Usage: ied checksum [content encoding] [size] [payload]");
+        return;
+    }
+
+    for (i, layer) in layers(&Recipe::parse(args)).iter().enumerate() {
+        println!("{} {} {} bytes crc32 {} adler32 {}", i, layer.encoding, layer.size,
+                hex(&layer.crc32), hex(&layer.adler32));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::adler::AdlerEngine;
+    use crate::payload::checksum::{ByteOrder, ChecksumEngine};
+    use crate::payload::crc32::Crc32Engine;
+
+    #[test]
+    fn test_layers() {
+        let args: Vec<String> = ["gzip,deflate", "1", "-l", "a"].iter()
+            .map(|s| s.to_string()).collect();
+        let recipe = Recipe::parse(&args);
+        let layers = layers(&recipe);
+        assert_eq!(layers.len(), 3);
+        assert_eq!(layers[0].encoding, "identity");
+        assert_eq!(layers[1].encoding, "gzip");
+        assert_eq!(layers[2].encoding, "deflate");
+        assert_eq!(layers[0].size, recipe.build().final_size());
+
+        let content = vec![b'a'; layers[0].size.to_u64_digits()[0] as usize];
+        let mut crc = Crc32Engine::new();
+        let mut adler = AdlerEngine::new();
+        crc.apply(&content);
+        adler.apply(&content);
+        assert_eq!(layers[0].crc32, *crc.bytes(ByteOrder::Big));
+        assert_eq!(layers[0].adler32, *adler.bytes(ByteOrder::Big));
+
+        /* the outermost layer is exactly what ied writes */
+        let mut data = Vec::<u8>::new();
+        recipe.build().write(&mut data).unwrap();
+        let mut crc = Crc32Engine::new();
+        crc.apply(&data);
+        assert_eq!(layers[2].size, BigUint::from(data.len()));
+        assert_eq!(layers[2].crc32, *crc.bytes(ByteOrder::Big));
+    }
+}
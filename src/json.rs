@@ -0,0 +1,245 @@
+use std::fmt;
+
+/* Just enough JSON to read and write our own manifests. Numbers are kept as their source text so
+ * that BigUints survive a round trip. */
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        if let Json::Object(fields) = self {
+            return fields.iter().find(|(k, _v)| k == key).map(|(_k, v)| v);
+        }
+        return Option::None;
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        if let Json::String(s) = self {
+            return Option::Some(s);
+        }
+        return Option::None;
+    }
+
+    pub fn as_number(&self) -> Option<&str> {
+        if let Json::Number(s) = self {
+            return Option::Some(s);
+        }
+        return Option::None;
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        if let Json::Array(a) = self {
+            return Option::Some(a);
+        }
+        return Option::None;
+    }
+
+    pub fn parse(text: &str) -> Option<Json> {
+        let mut parser = Parser {
+            data: text.as_bytes(),
+            pos: 0,
+        };
+        let ret = parser.value()?;
+        parser.skip_ws();
+        if parser.pos != parser.data.len() {
+            return Option::None;
+        }
+        return Option::Some(ret);
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    return write!(f, "\"");
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => return write!(f, "null"),
+            Json::Bool(b) => return write!(f, "{}", b),
+            Json::Number(n) => return write!(f, "{}", n),
+            Json::String(s) => return write_str(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                return write!(f, "]");
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write_str(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                return write!(f, "}}");
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_ws(&mut self) {
+        while self.pos < self.data.len() && b" \t\r\n".contains(&self.data[self.pos]) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_ws();
+        return self.data.get(self.pos).copied();
+    }
+
+    fn literal(&mut self, lit: &str, value: Json) -> Option<Json> {
+        if !self.data[self.pos..].starts_with(lit.as_bytes()) {
+            return Option::None;
+        }
+        self.pos += lit.len();
+        return Option::Some(value);
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        match self.peek()? {
+            b'n' => return self.literal("null", Json::Null),
+            b't' => return self.literal("true", Json::Bool(true)),
+            b'f' => return self.literal("false", Json::Bool(false)),
+            b'"' => return Option::Some(Json::String(self.string()?)),
+            b'[' => {
+                self.pos += 1;
+                let mut items = Vec::<Json>::new();
+                if self.peek()? == b']' {
+                    self.pos += 1;
+                    return Option::Some(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    match self.peek()? {
+                        b',' => self.pos += 1,
+                        b']' => break,
+                        _ => return Option::None,
+                    }
+                }
+                self.pos += 1;
+                return Option::Some(Json::Array(items));
+            }
+            b'{' => {
+                self.pos += 1;
+                let mut fields = Vec::<(String, Json)>::new();
+                if self.peek()? == b'}' {
+                    self.pos += 1;
+                    return Option::Some(Json::Object(fields));
+                }
+                loop {
+                    if self.peek()? != b'"' {
+                        return Option::None;
+                    }
+                    let key = self.string()?;
+                    if self.peek()? != b':' {
+                        return Option::None;
+                    }
+                    self.pos += 1;
+                    fields.push((key, self.value()?));
+                    match self.peek()? {
+                        b',' => self.pos += 1,
+                        b'}' => break,
+                        _ => return Option::None,
+                    }
+                }
+                self.pos += 1;
+                return Option::Some(Json::Object(fields));
+            }
+            _ => {
+                let start = self.pos;
+                while self.pos < self.data.len() && b"+-.0123456789eE".contains(&self.data[self.pos]) {
+                    self.pos += 1;
+                }
+                if start == self.pos {
+                    return Option::None;
+                }
+                let n = std::str::from_utf8(&self.data[start..self.pos]).ok()?;
+                return Option::Some(Json::Number(n.to_string()));
+            }
+        }
+    }
+
+    /* called with pos on the opening quote */
+    fn string(&mut self) -> Option<String> {
+        self.pos += 1;
+        let mut ret = Vec::<u8>::new();
+        loop {
+            let c = *self.data.get(self.pos)?;
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let e = *self.data.get(self.pos)?;
+                    self.pos += 1;
+                    match e {
+                        b'n' => ret.push(b'\n'),
+                        b'r' => ret.push(b'\r'),
+                        b't' => ret.push(b'\t'),
+                        b'b' => ret.push(0x08),
+                        b'f' => ret.push(0x0c),
+                        b'u' => {
+                            let hex = std::str::from_utf8(self.data.get(self.pos..self.pos + 4)?).ok()?;
+                            let c = char::from_u32(u32::from_str_radix(hex, 16).ok()?)?;
+                            self.pos += 4;
+                            let mut buf = [0; 4];
+                            ret.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                        }
+                        e => ret.push(e),
+                    }
+                }
+                c => ret.push(c),
+            }
+        }
+        return String::from_utf8(ret).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let text = "{\"a\":[1,\"two\\n\",true,null],\"b\":{\"c\":\"\\u0041\\\"\"},\"d\":\
+                    12345678901234567890123456789}";
+        let value = Json::parse(text).unwrap();
+        assert_eq!(value.get("b").unwrap().get("c").unwrap().as_str(), Option::Some("A\""));
+        assert_eq!(value.get("d").unwrap().as_number(), Option::Some("12345678901234567890123456789"));
+        assert_eq!(value.get("a").unwrap().as_array().unwrap().len(), 4);
+        assert_eq!(value.to_string(), text.replace("\\u0041", "A"));
+        assert!(Json::parse("{\"a\":1,}").is_none());
+        assert!(Json::parse("[1] 2").is_none());
+    }
+}
@@ -1,78 +1,60 @@
-use crate::payload::*;
-use std::str::FromStr;
-use std::io::Read;
+#![allow(
+    clippy::needless_return,
+    clippy::redundant_field_names,
+    clippy::unnecessary_cast,
+    clippy::needless_range_loop,
+    clippy::identity_op,
+    clippy::needless_late_init,
+)]
+
+use crate::recipe::Recipe;
 
 pub mod payload;
+pub mod range;
+pub mod recipe;
+pub mod cache;
+pub mod checksum;
+pub mod json;
+pub mod metrics;
+pub mod optimize;
+pub mod serve;
+pub mod websocket;
 
 fn main() {
-    let mut data = Vec::<Segment>::new();
     let args: Vec<String> = std::env::args().collect();
 
-    if args.len() < 3 {
-        println!("Usage: ied [content encoding] [size] [payload]");
+    if args.len() >= 2 && args[1] == "cache" {
+        cache::main(&args[2..]);
         return;
     }
 
-    let encoding = &args[1];
-    let size = num::BigUint::from_str(&args[2]).expect("Invalid size given");
-
-    let mut cur_arg = 3;
-    while cur_arg < args.len() {
-        if args[cur_arg] == "-f" {
-            cur_arg += 1;
-            if cur_arg >= args.len() {
-                panic!("-f: missing file");
-            }
-            let mut file = std::fs::File::open(&args[cur_arg]).expect("Failed to open file");
-            let mut contents: Vec<u8> = vec![];
-            file.read_to_end(&mut contents).expect("Failed to read file");
-
-            let block = Segment::Block(Block::new(contents.into_boxed_slice()));
-            data.push(block);
-
-            cur_arg += 1;
-            continue;
-        }
-
-        let byte: u8;
-        if args[cur_arg] == "-l" {
-            cur_arg += 1;
-            if cur_arg >= args.len() {
-                panic!("-l: missing character");
-            }
-            byte = args[cur_arg].chars().nth(0).expect("-l: missing character") as u8;
-            cur_arg += 1;
-        } else if args[cur_arg] == "-L" {
-            cur_arg += 1;
-            if cur_arg >= args.len() {
-                panic!("-L: missing character");
-            }
-            byte = args[cur_arg].parse::<u8>().expect("-L: invalid character");
-            cur_arg += 1;
-        } else {
-            panic!("Invalid flag {}", args[cur_arg]);
-        }
+    if args.len() >= 2 && args[1] == "checksum" {
+        checksum::main(&args[2..]);
+        return;
+    }
 
-        let bomb = Segment::Bomb(Bomb::new(Box::new([byte])));
-        data.push(bomb);
+    if args.len() >= 2 && args[1] == "optimize" {
+        optimize::main(&args[2..]);
+        return;
     }
 
-    let mut payload = Payload::new(data.into_boxed_slice());
-    let split = encoding.split(',');
+    if args.len() >= 2 && args[1] == "serve" {
+        serve::main(&args[2..]);
+        return;
+    }
 
-    for method_raw in split.into_iter() {
-        let method = method_raw.trim();
-        if method == "" {
-            continue;
-        } else if method == "gzip" {
-            payload = gzip(payload);
-        } else if method == "deflate" {
-            payload = zlib(payload);
-        } else {
-            panic!("Invalid method {}", method);
-        }
+    if args.len() < 3 {
+        println!("Usage: ied [content encoding] [size] [payload]");
+        println!("       ied cache build [directory] [recipes]");
+        println!("       ied checksum [content encoding] [size] [payload]");
+        println!("       ied optimize [max size] [content encodings] [payload]");
+        println!("       ied serve [address] [cache directory] [recipes]");
+        return;
     }
 
-    payload.fill(&size);
-    payload.write(&mut std::io::stdout());
+    let payload = Recipe::parse(&args[1..]).build();
+    let delivery = payload.write(&mut std::io::stdout()).expect("Write failed");
+    if delivery.disconnected {
+        eprintln!("Reader disconnected after {} of {} bytes", delivery.bytes, payload.size());
+    }
 }
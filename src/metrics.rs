@@ -0,0 +1,172 @@
+use crate::payload::Delivery;
+use num::BigUint;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/* Counters for one (rule, recipe) pair */
+#[derive(Default)]
+struct Counters {
+    requests: u64,
+    bytes_sent: BigUint,
+    complete: u64,
+    aborted: u64,
+    /* how much the delivered bytes would have inflated to */
+    decompressed: BigUint,
+}
+
+/* Metrics for served payloads, rendered in the Prometheus text format. Everything is behind a
+ * shared reference so that one instance can be used from every connection. */
+pub struct Metrics {
+    served: Mutex<BTreeMap<(String, String), Counters>>,
+    connections: AtomicI64,
+}
+
+/* Decrements a gauge when dropped, so a connection that panics or bails out early is still
+ * accounted for. */
+pub struct Gauge<'a> {
+    gauge: &'a AtomicI64,
+}
+
+impl Drop for Gauge<'_> {
+    fn drop(&mut self) {
+        self.gauge.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/* Label values can contain anything, escape them the way the exposition format wants. */
+fn escape(s: &str) -> String {
+    return s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+}
+
+/* The part of the final layer that the delivered bytes of a payload of size bytes expand to,
+ * assuming the ratio is the same all the way through. Good enough to count with, not exact. */
+pub fn decompressed_bytes(size: &BigUint, final_size: &BigUint, delivered: usize) -> BigUint {
+    if *size == BigUint::ZERO {
+        return BigUint::ZERO;
+    }
+    if BigUint::from(delivered) >= *size {
+        return final_size.clone();
+    }
+    return final_size * delivered / size;
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        return Metrics {
+            served: Mutex::new(BTreeMap::new()),
+            connections: AtomicI64::new(0),
+        };
+    }
+
+    /* Call when a connection is accepted, and keep the result around until it's closed. */
+    pub fn connection(&self) -> Gauge<'_> {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        return Gauge {
+            gauge: &self.connections,
+        };
+    }
+
+    /* Records one response. A delivery is complete if the client read all of it, which for a
+     * Range request is only part of the payload. size and final_size are the payload's, as sent
+     * and fully decompressed. */
+    pub fn record(&self, rule: &str, recipe: &str, size: &BigUint, final_size: &BigUint,
+                  delivery: &Delivery) {
+        let mut served = self.served.lock().unwrap();
+        let counters = served.entry((rule.to_string(), recipe.to_string())).or_default();
+        counters.requests += 1;
+        counters.bytes_sent += delivery.bytes;
+        if delivery.disconnected {
+            counters.aborted += 1;
+        } else {
+            counters.complete += 1;
+        }
+        counters.decompressed += decompressed_bytes(size, final_size, delivery.bytes);
+    }
+
+    pub fn render(&self) -> String {
+        let served = self.served.lock().unwrap();
+        let mut ret = String::new();
+
+        let mut family = |name: &str, kind: &str, help: &str,
+                          value: &dyn Fn(&Counters) -> String| {
+            writeln!(ret, "# HELP {} {}", name, help).unwrap();
+            writeln!(ret, "# TYPE {} {}", name, kind).unwrap();
+            for ((rule, recipe), counters) in served.iter() {
+                writeln!(ret, "{}{{rule=\"{}\",recipe=\"{}\"}} {}", name, escape(rule),
+                        escape(recipe), value(counters)).unwrap();
+            }
+        };
+
+        family("ied_requests_total", "counter", "Payloads served.",
+                &|c| c.requests.to_string());
+        family("ied_sent_bytes_total", "counter", "Bytes written to clients.",
+                &|c| c.bytes_sent.to_string());
+        family("ied_transfers_complete_total", "counter", "Payloads the client read in full.",
+                &|c| c.complete.to_string());
+        family("ied_transfers_aborted_total", "counter", "Payloads the client hung up on.",
+                &|c| c.aborted.to_string());
+        family("ied_decompressed_bytes_total", "counter",
+                "Bytes the delivered data decompresses to, estimated from final_size().",
+                &|c| c.decompressed.to_string());
+
+        writeln!(ret, "# HELP ied_active_connections Connections currently open.").unwrap();
+        writeln!(ret, "# TYPE ied_active_connections gauge").unwrap();
+        writeln!(ret, "ied_active_connections {}", self.connections.load(Ordering::Relaxed)).unwrap();
+
+        return ret;
+    }
+
+    /* The whole response for GET /metrics */
+    pub fn write_response(&self, output: &mut impl io::Write) -> io::Result<()> {
+        let body = self.render();
+        write!(output, "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        return output.flush();
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        return Metrics::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::*;
+
+    #[test]
+    fn test_render() {
+        let mut payload = gzip(Payload::new(Box::new([Segment::Bomb(Bomb::new(Box::new([0x61])))])));
+        payload.fill(&BigUint::from(10u8));
+        let size = payload.size().to_u64_digits()[0] as usize;
+
+        let metrics = Metrics::new();
+        let connection = metrics.connection();
+        metrics.record("bots", "html \"a\"", &payload.size(), &payload.final_size(), &Delivery {
+            bytes: size,
+            disconnected: false,
+        });
+        metrics.record("bots", "html \"a\"", &payload.size(), &payload.final_size(), &Delivery {
+            bytes: size / 2,
+            disconnected: true,
+        });
+
+        let text = metrics.render();
+        let labels = "{rule=\"bots\",recipe=\"html \\\"a\\\"\"}";
+        assert!(text.contains(&format!("ied_requests_total{} 2\n", labels)));
+        assert!(text.contains(&format!("ied_sent_bytes_total{} {}\n", labels, size + size / 2)));
+        assert!(text.contains(&format!("ied_transfers_complete_total{} 1\n", labels)));
+        assert!(text.contains(&format!("ied_transfers_aborted_total{} 1\n", labels)));
+        let decompressed = payload.final_size() + payload.final_size() * (size / 2) / size;
+        assert!(text.contains(&format!("ied_decompressed_bytes_total{} {}\n", labels, decompressed)));
+        assert!(text.contains("ied_active_connections 1\n"));
+
+        drop(connection);
+        assert!(metrics.render().contains("ied_active_connections 0\n"));
+    }
+}
@@ -0,0 +1,321 @@
+use crate::recipe::{split_encodings, Recipe};
+use num::BigUint;
+use std::str::FromStr;
+
+/* ied optimize: the layers and bomb size that make the most of a limit on how big the payload that
+ * gets sent can be.
+ *
+ * The bomb size only adds to the outermost layer, so the size of what's sent grows by the same
+ * amount for every byte of bomb, and two builds find the largest bomb that fits. The encodings are
+ * picked by trying every accepted one at each layer in turn (outermost first, since that's where
+ * overhead costs the most) and keeping whatever helps, for each number of layers until more stop
+ * helping.
+ *
+ * Each layer multiplies what a payload decodes to by up to 1032 for a few dozen bytes of its own, so
+ * for the largest final size, more layers keep winning until their overhead has used up almost all
+ * of the limit, which is far past the point of mattering to anyone. Layers are only added until the
+ * final size reaches a cutoff (-c, 2^64 bytes by default, which no reader will ever get through),
+ * or the cap on layers (-n). */
+pub struct Plan {
+    pub recipe: Recipe,
+    /* what gets sent, and what it all decodes to */
+    pub size: BigUint,
+    pub final_size: BigUint,
+}
+
+pub enum Goal {
+    /* the largest final size that fits, adding layers only until it's at least this much */
+    Largest(BigUint),
+    /* the smallest payload that decodes to at least this much */
+    Target(BigUint),
+}
+
+fn sizes(recipe: &mut Recipe, size: &BigUint) -> (BigUint, BigUint) {
+    recipe.size = size.clone();
+    let payload = recipe.build();
+    return (payload.size(), payload.final_size());
+}
+
+/* The largest bomb size that keeps the payload within max, as a plan */
+fn largest(mut recipe: Recipe, max: &BigUint) -> Option<Plan> {
+    let one = BigUint::from(1u8);
+    let (size_one, _) = sizes(&mut recipe, &one);
+    if size_one > *max {
+        return Option::None;
+    }
+    let (size_two, _) = sizes(&mut recipe, &BigUint::from(2u8));
+    let estimate = if size_two > size_one {
+        (max - &size_one) / (size_two - &size_one) + &one
+    } else {
+        one.clone()
+    };
+
+    /* Block headers can put the estimate a little too high, so the bomb size is searched for
+     * between 1 and it. lo always fits, and hi doesn't or is past the estimate. */
+    let mut lo = one;
+    let mut hi = estimate.clone();
+    if sizes(&mut recipe, &hi).0 <= *max {
+        lo = hi.clone();
+        hi += 1u8;
+    }
+    while &lo + 1u8 < hi {
+        let mid: BigUint = (&lo + &hi) / 2u8;
+        if sizes(&mut recipe, &mid).0 <= *max {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    let (size, final_size) = sizes(&mut recipe, &lo);
+    return Option::Some(Plan {
+        recipe: recipe,
+        size: size,
+        final_size: final_size,
+    });
+}
+
+/* The smallest bomb size that decodes to at least target, if that fits within max */
+fn smallest(recipe: Recipe, max: &BigUint, target: &BigUint) -> Option<Plan> {
+    let most = largest(recipe, max)?;
+    if most.final_size < *target {
+        return Option::None;
+    }
+
+    /* final_size(hi) is always at least target */
+    let mut recipe = most.recipe.clone();
+    let mut lo = BigUint::ZERO;
+    let mut hi = most.recipe.size.clone();
+    while &lo + 1u8 < hi {
+        let mid: BigUint = (&lo + &hi) / 2u8;
+        if sizes(&mut recipe, &mid).1 >= *target {
+            hi = mid;
+        } else {
+            lo = mid;
+        }
+    }
+    let (size, final_size) = sizes(&mut recipe, &hi);
+    return Option::Some(Plan {
+        recipe: recipe,
+        size: size,
+        final_size: final_size,
+    });
+}
+
+fn evaluate(recipe: Recipe, max: &BigUint, goal: &Goal) -> Option<Plan> {
+    return match goal {
+        Goal::Largest(_cutoff) => largest(recipe, max),
+        Goal::Target(target) => smallest(recipe, max, target),
+    };
+}
+
+/* Whether a plan with one more layer than best is worth having instead */
+fn worth_a_layer(plan: &Option<Plan>, best: &Option<Plan>, goal: &Goal) -> bool {
+    if !better(plan, best, goal) {
+        return false;
+    }
+    return match (goal, best) {
+        (Goal::Largest(cutoff), Option::Some(b)) => b.final_size < *cutoff,
+        _ => true,
+    };
+}
+
+fn better(plan: &Option<Plan>, than: &Option<Plan>, goal: &Goal) -> bool {
+    let (a, b) = match (plan, than) {
+        (Option::Some(a), Option::Some(b)) => (a, b),
+        (Option::Some(_), Option::None) => return true,
+        (Option::None, _) => return false,
+    };
+    return match goal {
+        Goal::Largest(_cutoff) => (&a.final_size, &b.size) > (&b.final_size, &a.size),
+        Goal::Target(_) => (&b.size, &a.final_size) > (&a.size, &b.final_size),
+    };
+}
+
+/* The best plan for the parts of recipe (its encodings and size are ignored) with up to max_layers
+ * layers, each one of the accepted encodings */
+pub fn optimize(recipe: &Recipe, accepted: &[String], max: &BigUint, goal: &Goal,
+        max_layers: usize) -> Option<Plan> {
+    let mut best = Option::<Plan>::None;
+    let mut layers = Vec::<String>::new();
+    for n in 1..=max_layers {
+        /* starting from the best with one layer fewer */
+        layers.push(accepted[0].clone());
+        let mut trial = recipe.clone();
+        trial.encodings = layers.clone();
+        let mut cur = evaluate(trial, max, goal);
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in (0..n).rev() {
+                for encoding in accepted.iter() {
+                    if layers[i] == *encoding {
+                        continue;
+                    }
+                    let mut trial = recipe.clone();
+                    trial.encodings = layers.clone();
+                    trial.encodings[i] = encoding.clone();
+                    let plan = evaluate(trial, max, goal);
+                    if better(&plan, &cur, goal) {
+                        layers[i] = encoding.clone();
+                        cur = plan;
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        /* every layer only adds overhead from here on, unless a target hasn't been reached yet */
+        if worth_a_layer(&cur, &best, goal) {
+            best = cur;
+        } else if best.is_some() || matches!(goal, Goal::Largest(_)) {
+            break;
+        }
+    }
+    return best;
+}
+
+/* ied optimize [max size] [content encodings] [-t target] [-c cutoff] [-n max layers]
+ * [payload]... */
+pub fn main(args: &[String]) {
+    if args.len() < 2 {
+        println!("Usage: ied optimize [max size] [content encodings] [-t target] [-c cutoff] \
+                [-n max layers] [payload]");
+        return;
+    }
+
+    let max = BigUint::from_str(&args[0]).expect("Invalid max size given");
+    let accepted = split_encodings(&args[1]);
+    if accepted.is_empty() {
+        panic!("No content encodings given");
+    }
+
+    let mut target = Option::<BigUint>::None;
+    let mut cutoff = BigUint::from(1u8) << 64;
+    let mut max_layers = 64;
+    let mut recipe_args = vec![accepted[0].clone(), "1".to_string()];
+    let mut cur_arg = 2;
+    while cur_arg < args.len() {
+        if args[cur_arg] == "-t" || args[cur_arg] == "-c" || args[cur_arg] == "-n" {
+            let value = args.get(cur_arg + 1)
+                .unwrap_or_else(|| panic!("{}: missing value", args[cur_arg]));
+            if args[cur_arg] == "-t" {
+                target = Option::Some(BigUint::from_str(value).expect("-t: invalid size"));
+            } else if args[cur_arg] == "-c" {
+                cutoff = BigUint::from_str(value).expect("-c: invalid size");
+            } else {
+                max_layers = value.parse::<usize>().expect("-n: invalid layer count");
+            }
+            cur_arg += 2;
+            continue;
+        }
+        recipe_args.push(args[cur_arg].clone());
+        cur_arg += 1;
+    }
+
+    let goal = match target {
+        Option::Some(target) => Goal::Target(target),
+        Option::None => Goal::Largest(cutoff),
+    };
+    let recipe = Recipe::parse(&recipe_args);
+    match optimize(&recipe, &accepted, &max, &goal, max_layers) {
+        Option::Some(plan) => {
+            println!("{}", plan.recipe.args().join(" "));
+            eprintln!("{} bytes -> {} bytes", plan.size, plan.final_size);
+        }
+        Option::None => eprintln!("No recipe fits in {} bytes", max),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recipe(text: &str) -> Recipe {
+        let args: Vec<String> = text.split_whitespace().map(|s| s.to_string()).collect();
+        return Recipe::parse(&args);
+    }
+
+    #[test]
+    fn test_largest() {
+        let accepted = ["gzip".to_string(), "deflate".to_string()];
+        let max = BigUint::from(500u32);
+        let goal = Goal::Largest(BigUint::from(1u8) << 64);
+        let plan = optimize(&recipe("gzip 1 -l a"), &accepted, &max, &goal, 64).unwrap();
+
+        /* the recipe it gives is the one it measured, and fits */
+        let payload = plan.recipe.build();
+        assert_eq!(payload.size(), plan.size);
+        assert_eq!(payload.final_size(), plan.final_size);
+        assert!(plan.size <= max);
+
+        /* and it beats stacking as many gzip layers by hand, or fewer */
+        for n in 1..=plan.recipe.encodings.len() {
+            let by_hand = largest(recipe(&format!("{} 1 -l a", vec!["gzip"; n].join(","))), &max).unwrap();
+            assert!(plan.final_size >= by_hand.final_size);
+        }
+
+        assert!(optimize(&recipe("gzip 1 -l a"), &accepted, &BigUint::from(10u32), &goal, 64)
+            .is_none());
+
+        /* one more byte of bomb doesn't fit */
+        for text in ["gzip 1 -l a", "gzip,deflate 1 -l a", "deflate-raw,gzip 1 -L 65"] {
+            for max in [300u32, 70000, 1000000] {
+                let max = BigUint::from(max);
+                let mut plan = largest(recipe(text), &max).unwrap();
+                assert!(plan.size <= max);
+                plan.recipe.size += 1u8;
+                assert!(plan.recipe.build().size() > max);
+            }
+        }
+    }
+
+    /* stops long before the cap on layers, once the final size reaches the cutoff */
+    #[test]
+    fn test_layers() {
+        let accepted = ["gzip".to_string(), "deflate".to_string()];
+        let max = BigUint::from(2000u32);
+        for bits in [32u32, 64, 128] {
+            let cutoff = BigUint::from(1u8) << bits;
+            let goal = Goal::Largest(cutoff.clone());
+            let plan = optimize(&recipe("gzip 1 -l a"), &accepted, &max, &goal, 64).unwrap();
+            let layers = plan.recipe.encodings.len();
+            assert!(layers < 16);
+            assert!(plan.final_size >= cutoff);
+
+            /* with one layer fewer it wasn't there yet */
+            let fewer = optimize(&recipe("gzip 1 -l a"), &accepted, &max, &goal, layers - 1)
+                .unwrap();
+            assert!(fewer.final_size < cutoff);
+        }
+
+        /* or at the cap */
+        let goal = Goal::Largest(BigUint::from(1u8) << 64);
+        let plan = optimize(&recipe("gzip 1 -l a"), &accepted, &max, &goal, 2).unwrap();
+        assert_eq!(plan.recipe.encodings.len(), 2);
+    }
+
+    #[test]
+    fn test_target() {
+        let accepted = ["gzip".to_string(), "deflate".to_string()];
+        let max = BigUint::from(100000u32);
+        let target = BigUint::from(10u32).pow(12);
+        let goal = Goal::Target(target.clone());
+        let plan = optimize(&recipe("gzip 1 -l a"), &accepted, &max, &goal, 64).unwrap();
+        assert!(plan.final_size >= target);
+        assert!(plan.size < BigUint::from(200u32));
+
+        /* with a single layer, one byte less of bomb falls short */
+        let target = BigUint::from(1000000u32);
+        let goal = Goal::Target(target.clone());
+        let plan = optimize(&recipe("gzip 1 -l a"), &accepted, &max, &goal, 1).unwrap();
+        assert!(plan.final_size >= target);
+        let mut shorter = plan.recipe.clone();
+        shorter.size -= 1u8;
+        assert!(shorter.build().final_size() < target);
+
+        /* a single layer can't get to 10^12 in 100000 bytes */
+        let goal = Goal::Target(BigUint::from(10u32).pow(12));
+        assert!(optimize(&recipe("gzip 1 -l a"), &accepted, &max, &goal, 1).is_none());
+    }
+}
@@ -1,12 +1,27 @@
 use num::BigUint;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::io;
-use crate::payload::checksum::ChecksumEngine;
+use std::rc::Rc;
+use crate::payload::checksum::{ByteOrder, ChecksumEngine};
 use crate::payload::adler::AdlerEngine;
 use crate::payload::crc32::Crc32Engine;
-
-mod checksum;
-mod adler;
-mod crc32;
+use crate::payload::corrupt::{corrupt, Corruption};
+use crate::payload::deflate::{plan, write_pieces, RepeatCode, Run};
+use crate::payload::huffman::BitWriter;
+use crate::payload::lz77::WINDOW;
+
+pub mod checksum;
+pub mod adler;
+pub mod crc32;
+pub mod crc;
+pub mod xxhash;
+pub mod corrupt;
+pub mod deflate;
+pub mod huffman;
+pub mod lz77;
+#[cfg(test)]
+pub mod inflate;
 
 /* A block is a "fixed" piece of data. This includes things like file headers/tails, as well as
  * checksums. Bombs are only guaranteed to be valid if their corresponding payload is fully
@@ -16,9 +31,12 @@ pub struct Block {
     len: usize,
 }
 
+type BlockFill = Box<dyn Fn(Option<&mut Payload>) -> Box<[u8]>>;
+type BombFill = Box<dyn Fn(Option<&mut Payload>, &BigUint)>;
+
 enum BlockData {
     Known(Box<[u8]>),
-    Unfilled(Box<dyn Fn(Option<&mut Payload>) -> Box<[u8]>>),
+    Unfilled(BlockFill),
 }
 
 /* A bomb is a very repetitive, highly compressible piece of data. The repeated bytes are an
@@ -39,9 +57,31 @@ pub struct Bomb {
     * The fill closure only informs the lower level of its size, it does not change the size of the
     * current payload.
     * */
-    fill: Box<dyn Fn(Option<&mut Payload>, &BigUint)>,
+    fill: BombFill,
+
+    /* This bomb is part i of n of one bomb the payload's size is given for, and Payload::fill gives
+     * it that share of the size. A gzip layer split into members makes parts of the bombs it
+     * splits, and every layer above keeps them. */
+    part: (usize, usize),
+}
+
+/* Segments with the same contents have the same checksum */
+#[derive(PartialEq, Eq, Hash)]
+enum SegmentKey<'a> {
+    Block(&'a [u8]),
+    Bomb(&'a [u8], &'a BigUint),
+}
+
+/* How much of a payload made it to the reader */
+pub struct Delivery {
+    pub bytes: usize,
+    /* the reader went away (EPIPE, ECONNRESET, ...) before everything was written */
+    pub disconnected: bool,
 }
 
+/* Bombs are written in chunks of about this many bytes */
+const BOMB_CHUNK: usize = 65536;
+
 /* A segment is either a block or a bomb */
 pub enum Segment {
     Block(Block),
@@ -51,12 +91,14 @@ pub enum Segment {
 pub struct Payload {
     pub data: Box<[Segment]>,
     child: Option<Box<Payload>>,
+    /* What of the child each gzip member encodes, empty for any other layer */
+    members: Vec<Vec<Portion>>,
 }
 
 impl Block {
     pub fn new(data: Box<[u8]>) -> Block {
         return Block {
-            len: (&data).len(),
+            len: data.len(),
             data: BlockData::Known(data),
         };
     }
@@ -74,9 +116,21 @@ impl Bomb {
             data: data,
             size: BigUint::ZERO,
             fill: Box::new(|_child, _size| {}),
+            part: (0, 1),
         };
     }
 
+    /* The share of a payload's bomb size that this bomb gets. The first size % n parts get a byte
+     * more than the rest. */
+    fn part_of(&self, size: &BigUint) -> BigUint {
+        let (i, n) = self.part;
+        let mut ret = size / n;
+        if BigUint::from(i) < size % n {
+            ret += 1u8;
+        }
+        return ret;
+    }
+
     pub fn fill(&mut self, child: Option<&mut Payload>, size: &BigUint) {
         (self.fill)(child, size);
         self.size = size.clone();
@@ -88,6 +142,7 @@ impl Payload {
         return Payload {
             data: data,
             child: Option::None,
+            members: Vec::new(),
         };
     }
 
@@ -109,84 +164,159 @@ impl Payload {
     pub fn fill(&mut self, bomb_size: &BigUint) {
         for segment in (*self.data).iter_mut() {
             if let Segment::Bomb(b) = segment {
+                let size = b.part_of(bomb_size);
                 if let Option::Some(child) = &mut self.child {
-                    b.fill(Option::Some(child), bomb_size);
+                    b.fill(Option::Some(child), &size);
                 } else {
-                    b.fill(Option::None, bomb_size);
+                    b.fill(Option::None, &size);
                 }
             }
         }
         self.fill_preset();
     }
 
-    pub fn write(&self, output: &mut impl io::Write) -> usize {
-        let mut size: usize = 0;
+    pub fn write(&self, output: &mut impl io::Write) -> io::Result<Delivery> {
+        return self.write_range(output, &BigUint::ZERO, &self.size());
+    }
+
+    /* Writes len bytes of this layer starting at offset start. Segments before the range are
+     * skipped using their sizes alone, so nothing before start is ever generated.
+     *
+     * The reader hanging up part way through is reported in the Delivery rather than as an error,
+     * since that's what a successful bomb usually looks like. */
+    pub fn write_range(&self, output: &mut impl io::Write, start: &BigUint, len: &BigUint)
+            -> io::Result<Delivery> {
+        let mut bytes: usize = 0;
+        let result = self.stream_range(output, start, len, &mut bytes);
+        return delivered(result, bytes);
+    }
+
+    /* write_range, but bytes is kept up to date as the output accepts data so that callers
+     * writing more than one thing can tell how far they got. */
+    pub fn stream_range(&self, output: &mut impl io::Write, start: &BigUint, len: &BigUint,
+                        bytes: &mut usize) -> io::Result<()> {
+        let mut skip = start.clone();
+        let mut left = len.clone();
         for segment in (*self.data).iter() {
+            if left == BigUint::ZERO {
+                break;
+            }
             match segment {
                 Segment::Block(b) => {
+                    if skip >= BigUint::from(b.len) {
+                        skip -= b.len;
+                        continue;
+                    }
                     let data: &[u8];
                     if let BlockData::Known(d) = &b.data {
                         data = d;
                     } else {
                         panic!("Trying to write uninitialized data");
                     }
-                    let s = output.write(data).expect("Write failed!");
-                    if s < data.len() {
-                        panic!("Write failed");
-                    }
-                    size += s;
+                    let from = biguint_to_u64(skip).unwrap() as usize;
+                    let to = match biguint_to_u64(left.clone()) {
+                        Option::Some(l) if l < (data.len() - from) as u64 => from + l as usize,
+                        _ => data.len(),
+                    };
+                    skip = BigUint::ZERO;
+
+                    write_all(output, &data[from..to], bytes)?;
+                    left -= to - from;
                 }
                 Segment::Bomb(b) => {
-                    let mut i = BigUint::ZERO;
-                    let mut idx = 0;
-                    while i < b.size {
-                        let slice = [b.data[idx]];
-                        let s = output.write(&slice).expect("Write failed.");
-                        if s < 1 {
-                            panic!("Write failed");
-                        }
-                        size += 1;
-                        idx = (idx + 1) % b.data.len();
-                        i += 1 as usize;
+                    if skip >= b.size {
+                        skip -= &b.size;
+                        continue;
+                    }
+                    let mut remaining = std::cmp::min(&b.size - &skip, left.clone());
+                    left -= &remaining;
+
+                    /* whole periods of the pattern, starting wherever the range starts */
+                    let idx = biguint_to_u64(&skip % b.data.len()).unwrap() as usize;
+                    let mut periods = std::cmp::max(BOMB_CHUNK / b.data.len(), 1);
+                    if let Option::Some(r) = biguint_to_u64(remaining.clone()) {
+                        periods = std::cmp::min(periods, (r as usize).div_ceil(b.data.len()));
+                    }
+                    let mut chunk = Vec::<u8>::with_capacity(periods * b.data.len());
+                    for _i in 0..periods {
+                        chunk.extend_from_slice(&b.data[idx..]);
+                        chunk.extend_from_slice(&b.data[..idx]);
+                    }
+                    skip = BigUint::ZERO;
+
+                    while remaining > BigUint::ZERO {
+                        let n = match biguint_to_u64(remaining.clone()) {
+                            Option::Some(r) if r < chunk.len() as u64 => r as usize,
+                            _ => chunk.len(),
+                        };
+                        write_all(output, &chunk[..n], bytes)?;
+                        remaining -= n;
                     }
                 }
             }
         }
-        return size
+        return output.flush();
     }
 
-    fn checksum(&self, engine: &mut impl ChecksumEngine) -> [u8; 4] {
+    /* The checksum of this layer, in the byte order the container wants it. */
+    pub fn checksum<E: ChecksumEngine + Clone>(&self, fresh: E, order: ByteOrder) -> Box<[u8]> {
+        return self.try_checksum(fresh, order).expect("Payload is too large to checksum");
+    }
+
+    /* The same, or None if the engine couldn't hash all of it (see ChecksumEngine::valid), in
+     * which case the container should leave the checksum out.
+     *
+     * Every distinct segment is checksummed once on a fresh engine and then combined in, so a
+     * block or bomb that shows up more than once (the same template or filler twice, say) only
+     * costs a combine the second time. Engines that can't combine just get everything in order. */
+    pub fn try_checksum<E: ChecksumEngine + Clone>(&self, fresh: E, order: ByteOrder)
+            -> Option<Box<[u8]>> {
+        let mut engine = fresh.clone();
+        let mut seen = HashMap::<SegmentKey, E>::new();
+
         for segment in (*self.data).iter() {
-            match segment {
+            if !E::COMBINES {
+                apply_segment(&mut engine, segment);
+                continue;
+            }
+
+            let key = match segment {
                 Segment::Block(b) => {
                     if let BlockData::Known(d) = &b.data {
-                        engine.apply(d);
+                        SegmentKey::Block(d)
                     } else {
                         panic!("Calculating checksum of uninitialized block");
                     }
                 }
-                Segment::Bomb(b) => {
-                    let full_blocks = b.size.clone() / b.data.len();
-                    let extra = biguint_to_u64(b.size.clone() % b.data.len())
-                        .expect("Failed to get extra bytes while calculating checksum");
+                Segment::Bomb(b) => SegmentKey::Bomb(&b.data, &b.size),
+            };
 
-                    engine.apply_rep(&b.data, full_blocks);
-                    engine.apply(&b.data[..(extra as usize)]);
-                }
+            let part = seen.entry(key).or_insert_with(|| {
+                let mut part = fresh.clone();
+                apply_segment(&mut part, segment);
+                part
+            });
+
+            match segment {
+                Segment::Block(b) => engine.combine(part, &BigUint::from(b.len)),
+                Segment::Bomb(b) => engine.combine(part, &b.size),
             }
         }
 
-        return engine.bytes();
+        if !engine.valid() || !seen.values().all(|part| part.valid()) {
+            return Option::None;
+        }
+        return Option::Some(engine.bytes(order));
     }
 
+    /* big endian, as zlib stores it */
     pub fn adler32(&self) -> [u8; 4] {
-        let mut engine = AdlerEngine::new();
-        return self.checksum(&mut engine);
+        return (*self.checksum(AdlerEngine::new(), ByteOrder::Big)).try_into().unwrap();
     }
 
+    /* big endian, gzip stores it the other way around */
     pub fn crc32(&self) -> [u8; 4] {
-        let mut engine = Crc32Engine::new();
-        return self.checksum(&mut engine);
+        return (*self.checksum(Crc32Engine::new(), ByteOrder::Big)).try_into().unwrap();
     }
 
     /* the size of this layer */
@@ -205,6 +335,23 @@ impl Payload {
         return ret;
     }
 
+    /* the layer this one encodes, if any */
+    pub fn child(&self) -> Option<&Payload> {
+        return self.child.as_deref();
+    }
+
+    /* the size of what each gzip member of this layer decompresses to, or nothing if this layer
+     * isn't gzip */
+    pub fn member_sizes(&self) -> Vec<BigUint> {
+        let child = match &self.child {
+            Option::Some(child) => child,
+            Option::None => return Vec::new(),
+        };
+        return self.members.iter()
+            .map(|portions| portions.iter().map(|p| p.size(child)).sum())
+            .collect();
+    }
+
     /* the size of the final layer */
     pub fn final_size(&self) -> BigUint {
         if let Option::Some(child) = &self.child {
@@ -215,9 +362,70 @@ impl Payload {
     }
 }
 
+fn apply_segment<E: ChecksumEngine>(engine: &mut E, segment: &Segment) {
+    match segment {
+        Segment::Block(b) => {
+            if let BlockData::Known(d) = &b.data {
+                engine.apply(d);
+            } else {
+                panic!("Calculating checksum of uninitialized block");
+            }
+        }
+        Segment::Bomb(b) => {
+            let full_blocks = b.size.clone() / b.data.len();
+            let extra = biguint_to_u64(b.size.clone() % b.data.len())
+                .expect("Failed to get extra bytes while calculating checksum");
+
+            engine.apply_rep(&b.data, full_blocks);
+            engine.apply(&b.data[..(extra as usize)]);
+        }
+    }
+}
+
+fn is_disconnect(e: &io::Error) -> bool {
+    return matches!(e.kind(),
+            io::ErrorKind::BrokenPipe |
+            io::ErrorKind::ConnectionReset |
+            io::ErrorKind::ConnectionAborted |
+            io::ErrorKind::NotConnected);
+}
+
+/* Writes all of data, retrying short and interrupted writes. bytes counts everything the output
+ * accepted, even if it fails part way through. */
+pub fn write_all(output: &mut impl io::Write, data: &[u8], bytes: &mut usize) -> io::Result<()> {
+    let mut rest = data;
+    while !rest.is_empty() {
+        match output.write(rest) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                *bytes += n;
+                rest = &rest[n..];
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    return Ok(());
+}
+
+/* Turns the result of a streaming write into a Delivery, treating disconnects as a normal end. */
+pub fn delivered(result: io::Result<()>, bytes: usize) -> io::Result<Delivery> {
+    match result {
+        Ok(()) => return Ok(Delivery {
+            bytes: bytes,
+            disconnected: false,
+        }),
+        Err(e) if is_disconnect(&e) => return Ok(Delivery {
+            bytes: bytes,
+            disconnected: true,
+        }),
+        Err(e) => return Err(e),
+    }
+}
+
 fn biguint_to_u64(num: BigUint) -> Option<u64> {
     let digits = num.to_u64_digits();
-    if digits.len() == 0 {
+    if digits.is_empty() {
         return Option::Some(0);
     }
     if digits.len() != 1 {
@@ -226,159 +434,242 @@ fn biguint_to_u64(num: BigUint) -> Option<u64> {
     return Option::Some(digits[0]);
 }
 
-/* Every message can be expressed as a series of Block, Bomb(0x55), Block, Bomb(0x55), ...
+/* What one DEFLATE stream encodes of a segment of its child: all of it, or one share of a bomb
+ * that's split between gzip members. The shares' sizes are only known once they're filled. */
+#[derive(Clone)]
+struct Portion {
+    index: usize,
+    share: Option<(Rc<RefCell<Vec<BigUint>>>, usize)>,
+    /* Which of the bomb's parts the share is, as in Bomb::part */
+    part: (usize, usize),
+    /* The share is a set number of bytes, written out as data instead of being a bomb of its
+     * own */
+    fixed: bool,
+}
+
+impl Portion {
+    fn whole(index: usize) -> Portion {
+        return Portion {
+            index: index,
+            share: Option::None,
+            part: (0, 1),
+            fixed: false,
+        };
+    }
+
+    /* Share i of a bomb split into count parts */
+    fn share(index: usize, shares: &Rc<RefCell<Vec<BigUint>>>, i: usize, count: usize) -> Portion {
+        return Portion {
+            index: index,
+            share: Option::Some((shares.clone(), i)),
+            part: (i, count),
+            fixed: false,
+        };
+    }
+
+    /* Whether this is where a repeat block goes */
+    fn is_bomb(&self, child: &Payload) -> bool {
+        return matches!(child.data[self.index], Segment::Bomb(_)) && !self.fixed;
+    }
+
+    /* Where in the bomb a share starts, and how big it is */
+    fn bounds(&self) -> Option<(BigUint, BigUint)> {
+        let (shares, i) = self.share.as_ref()?;
+        let shares = shares.borrow();
+        return Option::Some((shares[..*i].iter().sum(), shares[*i].clone()));
+    }
+
+    /* How far into the pattern the share starts */
+    fn phase(&self, pattern_len: usize) -> usize {
+        return match self.bounds() {
+            Option::Some((start, _size)) => biguint_to_u64(start % pattern_len).unwrap() as usize,
+            Option::None => 0,
+        };
+    }
+
+    fn size(&self, child: &Payload) -> BigUint {
+        if let Option::Some((_start, size)) = self.bounds() {
+            return size;
+        }
+        return match &child.data[self.index] {
+            Segment::Block(b) => BigUint::from(b.len),
+            Segment::Bomb(b) => b.size.clone(),
+        };
+    }
+}
+
+fn whole(payload: &Payload) -> Vec<Portion> {
+    return (0..payload.data.len()).map(Portion::whole).collect();
+}
+
+/* The checksum of only some portions of child, for a gzip member */
+fn portions_checksum<E: ChecksumEngine>(child: &Payload, portions: &[Portion], mut engine: E)
+        -> E {
+    for portion in portions.iter() {
+        let segment = &child.data[portion.index];
+        match (segment, portion.bounds()) {
+            (Segment::Bomb(b), Option::Some((_start, size))) => {
+                let phase = portion.phase(b.data.len());
+                let rotated = [&b.data[phase..], &b.data[..phase]].concat();
+                let extra = biguint_to_u64(&size % b.data.len()).unwrap() as usize;
+                engine.apply_rep(&rotated, size / b.data.len());
+                engine.apply(&rotated[..extra]);
+            }
+            _ => apply_segment(&mut engine, segment),
+        }
+    }
+    return engine;
+}
+
+/* Every message can be expressed as a series of Block, Bomb, Block, Bomb, ...
  *
  * Each block contains literal blocks, as well as the header for the next Bomb block. The size of
- * the Block can be statically determined, but its contents are determined at fill time. */
-fn deflate_to_vec(payload: &Payload, output: &mut Vec<Segment>) {
+ * the Block can be statically determined, but its contents are determined at fill time.
+ *
+ * A bomb of the child starts off as its pattern repeated up to the RepeatCode's period in literal
+ * bytes, and the rest of it is copied from that far back. */
+fn deflate_to_vec(payload: &Payload, portions: &[Portion], output: &mut Vec<Segment>,
+        options: &DeflateOptions) {
+    /* nothing at all, which still needs a last block: an empty fixed Huffman one */
+    if portions.is_empty() {
+        output.push(Segment::Block(Block::new(Box::new([0x03, 0x00]))));
+        return;
+    }
+
+    /* The code of the last bomb, and how its size left the tail after it */
+    let mut prev: Option<(Rc<RepeatCode>, Rc<Cell<usize>>)> = Option::None;
+
     let mut start = 0;
-    while start < payload.data.len() {
+    while start < portions.len() {
         let mut end = start;
-        let has_rep: bool;
+        let code: Option<(Rc<RepeatCode>, Rc<Cell<usize>>)>;
 
         /* Find the bounds of this Block */
         loop {
-            if end >= payload.data.len() {
-                has_rep = false;
+            if end >= portions.len() {
+                code = Option::None;
                 break;
             }
-            if let Segment::Bomb(b) = &payload.data[end] {
-                if b.data.len() != 1 {
-                    panic!("DEFLATE bomb has multibyte data");
+            if let (Segment::Bomb(b), true) =
+                    (&payload.data[portions[end].index], portions[end].is_bomb(payload)) {
+                if b.data.len() > options.window() {
+                    panic!("A {} byte bomb pattern doesn't fit in a {} byte DEFLATE window",
+                        b.data.len(), options.window());
                 }
-                has_rep = true;
+                let c = match options.byte {
+                    Option::Some(byte) => RepeatCode::for_byte(b.data.len(), byte, options.window())
+                        .unwrap_or_else(|| panic!("No DEFLATE code makes a bomb of byte {:#04x} \
+                            from a {} byte pattern", byte, b.data.len())),
+                    Option::None => RepeatCode::new(b.data.len()),
+                };
+                code = Option::Some((Rc::new(c), Rc::new(Cell::new(0))));
                 break;
             }
             end += 1;
         }
 
         /* Create this Block */
-        let start_c = start;
-        let mut payload_len: usize = 0;
-        let mut data_len: usize = 0;
-        let is_last = end+1 >= payload.data.len();
+        let gen_portions = portions[start..std::cmp::min(end + 1, portions.len())].to_vec();
+        let is_last = end+1 >= portions.len();
 
-        for i in start..=end {
-            if i >= payload.data.len() {
-                break;
-            }
-            match &payload.data[i] {
-                Segment::Block(b) => {
-                    data_len += b.len;
+        /* The child's data up to and including the start of its bomb, in runs of what's known now
+         * and what isn't */
+        let mut data_len: usize = 0;
+        let mut known = Vec::<Vec<u8>>::new();
+        let mut unknown = Vec::<Option<usize>>::new();
+        for portion in gen_portions.iter() {
+            let (len, bytes) = match &payload.data[portion.index] {
+                Segment::Block(b) => match &b.data {
+                    BlockData::Known(data) => (b.len, Option::Some(data.to_vec())),
+                    BlockData::Unfilled(_fill) => (b.len, Option::None),
+                },
+                /* small_isize's bytes are only made when the block is filled */
+                Segment::Bomb(_b) if portion.fixed => {
+                    (biguint_to_u64(portion.size(payload)).unwrap() as usize, Option::None)
+                }
+                /* a share after the first starts wherever the one before it ended */
+                Segment::Bomb(b) if b.data.len() > 1
+                        && portion.share.as_ref().is_some_and(|(_shares, i)| *i > 0) => {
+                    (code.as_ref().unwrap().0.period(), Option::None)
                 }
                 Segment::Bomb(b) => {
-                    data_len += b.data.len();
+                    let period = code.as_ref().unwrap().0.period();
+                    (period, Option::Some(b.data.iter().cycle().take(period).copied().collect()))
+                }
+            };
+            data_len += len;
+            match (bytes, unknown.last_mut()) {
+                (Option::Some(bytes), Option::Some(Option::None)) => {
+                    known.last_mut().unwrap().extend_from_slice(&bytes);
+                }
+                (Option::Some(bytes), _) => {
+                    known.push(bytes);
+                    unknown.push(Option::None);
+                }
+                (Option::None, Option::Some(Option::Some(n))) => *n += len,
+                (Option::None, _) => {
+                    known.push(Vec::new());
+                    unknown.push(Option::Some(len));
                 }
             }
         }
-
-        /* The maximum length of an uncompressed block is 0xffff bytes, each uncompressed block
-         * header is 5 bytes. */
-        let num_blocks = (data_len + 0xffff - 1) / 0xffff;
-        payload_len += num_blocks * 5;
-        payload_len += data_len;
-
-        /* The length of the following Bomb header (if there is one) is 13 bytes. */
-        if has_rep {
-            payload_len += 13;
+        let runs: Vec<Run> = known.iter().zip(unknown.iter()).map(|(bytes, n)| match n {
+            Option::Some(n) => Run::Unknown(*n),
+            Option::None => Run::Known(bytes),
+        }).collect();
+
+        /* Stored blocks (at most 0xffff bytes, with a 5 byte header) for what isn't known, and
+         * Huffman coded ones for what is when that's shorter. The tail of the last bomb's block
+         * comes first, and the header of the following Bomb (if there is one) last. */
+        let (pieces, mut payload_len) = plan(&runs, prev.as_ref().map(|(c, _phase)| c.tail_bytes()),
+                code.is_some(), !options.stored_literals, options.window());
+        if let Option::Some((c, _phase)) = &code {
+            payload_len += c.head_bytes();
         }
 
+        let gen_prev = prev.clone();
+        let gen_code = code.clone();
         let gen_block = move |child_op: Option<&mut Payload>| -> Box<[u8]> {
-            let mut ret: Vec<u8> = Vec::new();
-            let mut last_block: usize = 0;
-            let mut last_bit: u8 = 0;
-
-            /* the block of the child we're writing */
-            let mut child_idx: usize = start_c;
-            /* the index within that block */
-            let mut child_pos: usize = 0;
+            let mut w = BitWriter::new();
 
             let child = child_op.expect("Trying to fill a block with no child");
 
-            /* if we saw a bomb last time, \x02 */
-            if start_c != 0 {
-                last_block = ret.len();
-                last_bit = 0x20;
-                ret.push(0x05);
-            }
-
-            let mut this_start = 0;
-            while this_start < data_len {
-                let this_end = std::cmp::min(this_start + 0xffff, data_len);
-                let this_len = this_end - this_start;
-
-                /* start of an uncompressed block. note that if the previous block was a bomb, we
-                 * write these bits there. */
-                if start_c == 0 || this_start != 0 {
-                    last_block = ret.len();
-                    last_bit = 0x01;
-                    ret.push(0x00);
-                }
-
-                /* uncompressed block length */
-                ret.push((this_len & 0xff) as u8);
-                ret.push((this_len >> 8)   as u8);
-
-                /* ones compliment of the block length */
-                let inverse_len = !this_len;
-                ret.push((inverse_len & 0xff) as u8);
-                ret.push((inverse_len >> 8)   as u8);
-
-                /* data of uncompressed block */
-                for _i in this_start..this_end {
-                    let byte: u8;
-                    match &child.data[child_idx] {
-                        Segment::Block(b) => {
-                            if let BlockData::Known(data) = &b.data {
-                                byte = data[child_pos];
-                                child_pos += 1;
-                                if child_pos >= data.len() {
-                                    child_idx += 1;
-                                    child_pos = 0;
-                                }
-                            } else {
-                                panic!("Filling in block with unfilled child");
-                            }
-                        }
-                        Segment::Bomb(b) => {
-                            byte = b.data[child_pos];
-                            child_pos += 1;
-                            if child_pos >= b.data.len() {
-                                child_idx += 1;
-                                child_pos = 0;
-                            }
+            /* the child's data up to and including the start of its bomb */
+            let mut data = Vec::<u8>::with_capacity(data_len);
+            for portion in gen_portions.iter() {
+                match &child.data[portion.index] {
+                    Segment::Block(b) => {
+                        if let BlockData::Known(bytes) = &b.data {
+                            data.extend_from_slice(bytes);
+                        } else {
+                            panic!("Filling in block with unfilled child");
                         }
                     }
-                    ret.push(byte);
+                    Segment::Bomb(b) => {
+                        let len = if portion.fixed {
+                            biguint_to_u64(portion.size(child)).unwrap() as usize
+                        } else {
+                            gen_code.as_ref().unwrap().0.period()
+                        };
+                        let phase = portion.phase(b.data.len());
+                        data.extend(b.data.iter().cycle().skip(phase).take(len));
+                    }
                 }
-
-                this_start = this_end;
             }
 
-            if has_rep {
-                /* there is a bomb after this, so we write the header of the next block */
-                last_block = ret.len();
-                ret.push(0xec);
-                ret.push(0xc0);
-                ret.push(0x81);
-                ret.push(0x00);
-                ret.push(0x00);
-                ret.push(0x00);
-                ret.push(0x00);
-                ret.push(0x00);
-                ret.push(0x90);
-                ret.push(0xff);
-                ret.push(0x6b);
-                ret.push(0x23);
-                ret.push(0x54);
+            if let Option::Some((prev_code, phase)) = &gen_prev {
+                prev_code.write_tail(&mut w, phase.get(), false);
             }
 
-            if is_last {
-                /* there is no bomb after this, so we set the BFINAL bit */
-                ret[last_block] |= last_bit;
+            /* the last blocks if nothing comes after them */
+            write_pieces(&mut w, &pieces, &data, is_last && gen_code.is_none());
+
+            /* there is a bomb after this, so we write the header of its block */
+            if let Option::Some((c, phase)) = &gen_code {
+                c.write_head(&mut w, is_last, phase.get());
             }
 
-            return ret.as_slice().into();
+            return w.finish().into_boxed_slice();
         };
 
         let block = Segment::Block(Block {
@@ -387,12 +678,24 @@ fn deflate_to_vec(payload: &Payload, output: &mut Vec<Segment>) {
         });
         output.push(block);
 
-        if has_rep {
+        prev = Option::None;
+        if let Option::Some((c, phase)) = code {
+            let fill_code = c.clone();
+            let fill_phase = phase.clone();
+            let fill_portion = portions[end].clone();
             let fill = move |child_op: Option<&mut Payload>, size: &BigUint| {
-                let child_size = size * 1032u16 + 1291u16;
+                let mut child_size = fill_code.expanded(size, is_last);
+                fill_phase.set(fill_code.phase(size));
+                /* a share fills the whole bomb with every share so far, the last one with all
+                 * of them */
+                if let Option::Some((shares, i)) = &fill_portion.share {
+                    let mut shares = shares.borrow_mut();
+                    shares[*i] = child_size;
+                    child_size = shares.iter().sum();
+                }
                 let child = child_op.expect("Trying to fill DEFLATE bomb with no child");
                 if let Segment::Bomb(b) =
-                        &mut child.data[end] {
+                        &mut child.data[fill_portion.index] {
                     if let Option::Some(grandchild) = &mut child.child {
                         b.fill(Option::Some(grandchild), &child_size);
                     } else {
@@ -401,49 +704,144 @@ fn deflate_to_vec(payload: &Payload, output: &mut Vec<Segment>) {
                 }
             };
 
+            /* a part of a part of the child's bomb */
+            let (i, n) = match &payload.data[portions[end].index] {
+                Segment::Bomb(b) => b.part,
+                Segment::Block(_b) => (0, 1),
+            };
+            let (j, k) = portions[end].part;
             let bomb = Segment::Bomb(Bomb {
-                data: Box::new([0x55]),
+                data: c.pattern(),
                 size: BigUint::ZERO,
                 fill: Box::new(fill),
+                part: (i * k + j, n * k),
             });
 
             output.push(bomb);
+            prev = Option::Some((c, phase));
         }
 
         start = end + 1;
     }
 
-    if let Segment::Bomb(_b) = &payload.data[payload.data.len() - 1] {
-        let f = Segment::Block(Block::new(Box::new([0x05])));
-        output.push(f);
+    /* the end of the last bomb's block, which is the last block */
+    if let Option::Some((prev_code, phase)) = prev {
+        let len = prev_code.tail_bytes();
+        let tail = move |_child_op: Option<&mut Payload>| -> Box<[u8]> {
+            let mut w = BitWriter::new();
+            prev_code.write_tail(&mut w, phase.get(), true);
+            return w.finish().into_boxed_slice();
+        };
+        output.push(Segment::Block(Block {
+            data: BlockData::Unfilled(Box::new(tail)),
+            len: len,
+        }));
+    }
+}
+
+/* Per layer settings for the DEFLATE stream itself, in any container */
+#[derive(Default)]
+pub struct DeflateOptions {
+    /* Literals go in stored blocks only, like they used to, instead of being compressed when
+     * they're known in advance */
+    pub stored_literals: bool,
+    /* The byte bombs in this layer are made of, or None for 0x55. 0x55 (like 0x00 and a few
+     * others) gets the best ratio there is, and the rest at least a quarter of it. */
+    pub byte: Option<u8>,
+    /* How far back matches can look, as a power of two from 8 to 15, or None for DEFLATE's own
+     * 2^15. For readers that ask for less, like a WebSocket client's server_max_window_bits. */
+    pub window_bits: Option<u8>,
+}
+
+impl DeflateOptions {
+    fn window(&self) -> usize {
+        return match self.window_bits {
+            Option::Some(bits) => 1 << bits,
+            Option::None => WINDOW,
+        };
     }
 }
 
 pub fn deflate_raw(payload: Payload) -> Payload {
+    return deflate_raw_with(payload, &DeflateOptions::default());
+}
+
+pub fn deflate_raw_with(payload: Payload, options: &DeflateOptions) -> Payload {
     let mut blocks = Vec::<Segment>::new();
-    deflate_to_vec(&payload, &mut blocks);
+    deflate_to_vec(&payload, &whole(&payload), &mut blocks, options);
 
     return Payload {
         data: blocks.into_boxed_slice(),
         child: Option::Some(Box::new(payload)),
+        members: Vec::new(),
     };
 }
 
+/* Per layer settings for zlib() */
+#[derive(Default)]
+pub struct ZlibOptions {
+    pub deflate: DeflateOptions,
+    /* The compression level (0 to 9) FLEVEL claims, or None for fastest. Nothing checks it. */
+    pub level: Option<u8>,
+    pub adler32: Option<Corruption>,
+}
+
+/* Per layer settings for gzip() */
+#[derive(Default)]
+pub struct GzipOptions {
+    pub deflate: DeflateOptions,
+    /* The compression level (0 to 9) XFL claims, or None for maximum compression */
+    pub level: Option<u8>,
+    pub mtime: u32,
+    /* The OS byte, or None for unknown (0xff) */
+    pub os: Option<u8>,
+    /* FNAME and FCOMMENT, without their terminating zero */
+    pub name: Option<Vec<u8>>,
+    pub comment: Option<Vec<u8>>,
+    /* FEXTRA subfields, each a 2 byte ID and its data */
+    pub extra: Vec<([u8; 2], Vec<u8>)>,
+    /* FHCRC, the low 2 bytes of the CRC-32 of the header before it */
+    pub header_crc: bool,
+    /* How many members to split the stream into, each with its own header and trailer for its
+     * part of the child. 0 and 1 are both one member. */
+    pub members: usize,
+    /* A member after those with only this many bytes of the last bomb (and whatever follows it),
+     * so the ISIZE at the very end of the stream, which is the one readers like gzip -l go by, is
+     * small and true */
+    pub small_isize: Option<usize>,
+    pub crc32: Option<Corruption>,
+    pub isize: Option<Corruption>,
+}
+
 pub fn zlib(payload: Payload) -> Payload {
+    return zlib_with(payload, &ZlibOptions::default());
+}
+
+pub fn zlib_with(payload: Payload, options: &ZlibOptions) -> Payload {
     let mut blocks = Vec::<Segment>::new();
 
-    /* zlib header: DEFLATE, fastest compression */
+    /* zlib header: DEFLATE, and FLEVEL the way zlib itself picks it */
+    let cmf = 0x08;
+    let flevel = match options.level {
+        Option::None | Option::Some(0..=1) => 0,
+        Option::Some(2..=5) => 1,
+        Option::Some(6) => 2,
+        Option::Some(_) => 3,
+    };
+    let flags = flevel << 6;
+    let fcheck = 31 - (cmf as u16 * 256 + flags as u16) % 31;
     blocks.push(Segment::Block(Block::new(Box::new([
-        0x08,  /* CMF */
-        0x1d,  /* FLAGS */
+        cmf,                     /* CMF */
+        flags | fcheck as u8,    /* FLAGS */
     ]))));
 
-    deflate_to_vec(&payload, &mut blocks);
+    deflate_to_vec(&payload, &whole(&payload), &mut blocks, &options.deflate);
 
-    fn adler32(child_op: Option<&mut Payload>) -> Box<[u8]> {
+    let adler_corruption = options.adler32;
+    let adler32 = move |child_op: Option<&mut Payload>| -> Box<[u8]> {
         let child = child_op.expect("Calculating Adler-32 checksum of invalid child");
-        return Box::new(child.adler32());
-    }
+        return corrupt(child.checksum(AdlerEngine::new(), ByteOrder::Big), adler_corruption, false);
+    };
 
     /* Adler-32 checksum */
     let checksum = Block {
@@ -455,58 +853,502 @@ pub fn zlib(payload: Payload) -> Payload {
     return Payload {
         data: blocks.into_boxed_slice(),
         child: Option::Some(Box::new(payload)),
+        members: Vec::new(),
     };
 }
 
-pub fn gzip(payload: Payload) -> Payload {
-    let mut blocks = Vec::<Segment>::new();
+/* The child split between count gzip members. Each split falls inside a bomb, spread evenly over
+ * the bombs, so there's only ever one member of a child without any. With a last member, the last
+ * that many bytes of the last bomb and everything after it get a member of their own, which needs
+ * the child to have a bomb. */
+fn split_members(payload: &Payload, count: usize, last: Option<usize>) -> Vec<Vec<Portion>> {
+    let bombs: Vec<usize> = (0..payload.data.len())
+        .filter(|i| matches!(payload.data[*i], Segment::Bomb(_)))
+        .collect();
+    let splits = if bombs.is_empty() { 0 } else { count.saturating_sub(1) };
+
+    let mut ret = vec![Vec::<Portion>::new()];
+    for i in 0..payload.data.len() {
+        let n = match bombs.iter().position(|b| *b == i) {
+            Option::Some(j) => splits / bombs.len() + (j < splits % bombs.len()) as usize,
+            Option::None => 0,
+        };
+        if n == 0 {
+            ret.last_mut().unwrap().push(Portion::whole(i));
+            continue;
+        }
 
-    /* gzip header */
-    blocks.push(Segment::Block(Block::new(Box::new([
+        let shares = Rc::new(RefCell::new(vec![BigUint::ZERO; n + 1]));
+        for j in 0..=n {
+            if j > 0 {
+                ret.push(Vec::new());
+            }
+            ret.last_mut().unwrap().push(Portion::share(i, &shares, j, n + 1));
+        }
+    }
+
+    if last.is_some() && bombs.is_empty() {
+        panic!("small_isize needs a bomb to take the last member from, and there's none");
+    }
+    if let (Option::Some(len), Option::Some(last_bomb)) = (last, bombs.last()) {
+        let members = ret.last_mut().unwrap();
+        let at = members.iter().position(|p| p.index == *last_bomb).unwrap();
+        let mut member = members.split_off(at + 1);
+        if len > 0 {
+            let shares = match &members[at].share {
+                Option::Some((shares, _i)) => shares.clone(),
+                Option::None => {
+                    let shares = Rc::new(RefCell::new(vec![BigUint::ZERO]));
+                    members[at] = Portion::share(*last_bomb, &shares, 0, 1);
+                    shares
+                }
+            };
+            shares.borrow_mut().push(BigUint::from(len));
+            let mut portion = Portion::share(*last_bomb, &shares, shares.borrow().len() - 1, 1);
+            portion.part = (0, 1);
+            portion.fixed = true;
+            member.insert(0, portion);
+        }
+        ret.push(member);
+    }
+    return ret;
+}
+
+/* gzip's ISIZE: the size mod 2^32, little endian */
+fn isize_bytes(len: &BigUint) -> [u8; 4] {
+    return (biguint_to_u64(len % (1u64 << 32)).unwrap() as u32).to_le_bytes();
+}
+
+fn gzip_header(options: &GzipOptions) -> Box<[u8]> {
+    let mtime = options.mtime.to_le_bytes();
+    let xfl = match options.level {
+        Option::None | Option::Some(9) => 0x02,
+        Option::Some(1) => 0x04,
+        Option::Some(_) => 0x00,
+    };
+    let flags = (options.header_crc as u8) << 1
+        | (!options.extra.is_empty() as u8) << 2
+        | (options.name.is_some() as u8) << 3
+        | (options.comment.is_some() as u8) << 4;
+    let mut header = vec![
         0x1f, 0x8b,              /* ID1, ID2 */
         0x08,                    /* CM (DEFLATE) */
-        0x00,                    /* FLG (no flags) */
-        0x00, 0x00, 0x00, 0x00,  /* MTIME (no time available) */
-        0x02,                    /* XFL, maximum compression, slowest algorithm */
-        0xff,                    /* OS (unknown) */
-    ]))));
+        flags,                   /* FLG */
+        mtime[0], mtime[1],      /* MTIME (0 for no time available) */
+        mtime[2], mtime[3],
+        xfl,                     /* XFL, 2 for maximum compression and 4 for fastest */
+        options.os.unwrap_or(0xff),  /* OS (unknown unless given) */
+    ];
+
+    if !options.extra.is_empty() {
+        let mut extra = Vec::<u8>::new();
+        for (id, data) in options.extra.iter() {
+            let len = u16::try_from(data.len()).expect("gzip extra subfield too long");
+            extra.extend_from_slice(id);
+            extra.extend_from_slice(&len.to_le_bytes());
+            extra.extend_from_slice(data);
+        }
+        let xlen = u16::try_from(extra.len()).expect("gzip extra field too long");
+        header.extend_from_slice(&xlen.to_le_bytes());
+        header.extend_from_slice(&extra);
+    }
+    for field in [&options.name, &options.comment].into_iter().flatten() {
+        if field.contains(&0) {
+            panic!("gzip name or comment contains a zero byte");
+        }
+        header.extend_from_slice(field);
+        header.push(0);
+    }
+    if options.header_crc {
+        let mut crc = Crc32Engine::new();
+        crc.apply(&header);
+        header.extend_from_slice(&crc.bytes(ByteOrder::Little)[..2]);
+    }
+    return header.into_boxed_slice();
+}
 
-    deflate_to_vec(&payload, &mut blocks);
+pub fn gzip(payload: Payload) -> Payload {
+    return gzip_with(payload, &GzipOptions::default());
+}
 
-    fn crc32(child_op: Option<&mut Payload>) -> Box<[u8]> {
-        let child = child_op.expect("Calculating CRC-32 checksum of invalid child");
-        let mut val = child.crc32();
-        val.reverse();
-        return Box::new(val);
-    }
+pub fn gzip_with(payload: Payload, options: &GzipOptions) -> Payload {
+    let mut blocks = Vec::<Segment>::new();
 
-    /* CRC-32 checksum */
-    let checksum = Block {
-        data: BlockData::Unfilled(Box::new(crc32)),
-        len: 4,
-    };
-    blocks.push(Segment::Block(checksum));
+    let members = split_members(&payload, options.members, options.small_isize);
+    let split = members.len() > 1;
+    let kept = members.clone();
+    for portions in members {
+        blocks.push(Segment::Block(Block::new(gzip_header(options))));
+
+        deflate_to_vec(&payload, &portions, &mut blocks, &options.deflate);
+
+        let crc_corruption = options.crc32;
+        let crc_portions = portions.clone();
+        let crc32 = move |child_op: Option<&mut Payload>| -> Box<[u8]> {
+            let child = child_op.expect("Calculating CRC-32 checksum of invalid child");
+            let crc = if split {
+                portions_checksum(child, &crc_portions, Crc32Engine::new()).bytes(ByteOrder::Little)
+            } else {
+                child.checksum(Crc32Engine::new(), ByteOrder::Little)
+            };
+            return corrupt(crc, crc_corruption, true);
+        };
 
-    /* ISIZE */
-    fn len(child_op: Option<&mut Payload>) -> Box<[u8]> {
-        let child = child_op.expect("Calculating length of invalid child");
-        let len = child.size();
-        let lenu32 = len.to_u32_digits()[0];
-        return Box::new([
-            (lenu32) as u8,
-            (lenu32 >> 8) as u8,
-            (lenu32 >> 16) as u8,
-            (lenu32 >> 24) as u8,
-        ]);
-    }
-    let length = Block {
-        data: BlockData::Unfilled(Box::new(len)),
-        len: 4,
-    };
-    blocks.push(Segment::Block(length));
+        /* CRC-32 checksum */
+        let checksum = Block {
+            data: BlockData::Unfilled(Box::new(crc32)),
+            len: 4,
+        };
+        blocks.push(Segment::Block(checksum));
+
+        /* ISIZE */
+        let isize_corruption = options.isize;
+        let len = move |child_op: Option<&mut Payload>| -> Box<[u8]> {
+            let child = child_op.expect("Calculating length of invalid child");
+            let len: BigUint = portions.iter().map(|p| p.size(child)).sum();
+            return corrupt(Box::new(isize_bytes(&len)), isize_corruption, true);
+        };
+        let length = Block {
+            data: BlockData::Unfilled(Box::new(len)),
+            len: 4,
+        };
+        blocks.push(Segment::Block(length));
+    }
 
     return Payload {
         data: blocks.into_boxed_slice(),
         child: Option::Some(Box::new(payload)),
+        members: kept,
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::crc::*;
+    use crate::payload::inflate::inflate;
+    use crate::payload::xxhash::Xxh64Engine;
+
+    /* accepts at most 3 bytes per write, and hangs up after limit bytes */
+    struct Flaky {
+        data: Vec<u8>,
+        limit: usize,
+        interrupted: bool,
+    }
+
+    impl io::Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.interrupted = !self.interrupted;
+            if self.interrupted {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            if self.data.len() >= self.limit {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            let n = std::cmp::min(std::cmp::min(buf.len(), 3), self.limit - self.data.len());
+            self.data.extend_from_slice(&buf[..n]);
+            return Ok(n);
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    #[test]
+    fn test_write_disconnect() {
+        let mut payload = gzip(Payload::new(Box::new([
+            Segment::Block(Block::new(Box::new(*b"<p>"))),
+            Segment::Bomb(Bomb::new(Box::new([0x61]))),
+        ])));
+        payload.fill(&BigUint::from(100u8));
+
+        let mut full = Vec::<u8>::new();
+        let delivery = payload.write(&mut full).unwrap();
+        assert_eq!(delivery.bytes, full.len());
+        assert!(!delivery.disconnected);
+
+        let mut all = Flaky { data: vec![], limit: usize::MAX, interrupted: false };
+        let delivery = payload.write(&mut all).unwrap();
+        assert_eq!(all.data, full);
+        assert!(!delivery.disconnected);
+
+        let mut part = Flaky { data: vec![], limit: 50, interrupted: false };
+        let delivery = payload.write(&mut part).unwrap();
+        assert_eq!(delivery.bytes, 50);
+        assert!(delivery.disconnected);
+        assert_eq!(part.data, &full[..50]);
+    }
+
+    /* every layer inflates to exactly the one under it */
+    #[test]
+    fn test_deflate_raw() {
+        let mut payload = deflate_raw(deflate_raw(Payload::new(Box::new([
+            Segment::Block(Block::new(Box::new(*b"<p>"))),
+            Segment::Bomb(Bomb::new(Box::new(*b"ab"))),
+            Segment::Block(Block::new(vec![0x42; 70000].into_boxed_slice())),
+            Segment::Bomb(Bomb::new(Box::new([0x61]))),
+            Segment::Bomb(Bomb::new(Box::new(*b"xyz"))),
+        ]))));
+        payload.fill(&BigUint::from(10u8));
+
+        let mut layer = Option::Some(&payload);
+        while let Option::Some(l) = layer {
+            let mut data = Vec::<u8>::new();
+            l.write(&mut data).unwrap();
+            assert_eq!(BigUint::from(data.len()), l.size());
+            if let Option::Some(child) = l.child() {
+                let mut expected = Vec::<u8>::new();
+                child.write(&mut expected).unwrap();
+                assert_eq!(inflate(&data), Option::Some((expected, data.len())));
+            }
+            layer = l.child();
+        }
+    }
+
+    /* What a real inflater makes of a layer */
+    fn real_inflate(layer: &str, data: &[u8]) -> Vec<u8> {
+        let inflated = match layer {
+            "gzip" => {
+                /* no optional header fields, so the DEFLATE data starts 10 bytes in */
+                assert_eq!(data[3], 0);
+                miniz_oxide::inflate::decompress_to_vec(&data[10..data.len() - 8])
+            }
+            "zlib" => miniz_oxide::inflate::decompress_to_vec_zlib(data),
+            _ => miniz_oxide::inflate::decompress_to_vec(data),
+        };
+        return inflated.unwrap();
+    }
+
+    /* A real inflater gets back through nested layers to the innermost payload */
+    #[test]
+    fn test_real_inflater() {
+        for layers in [["gzip", "zlib"], ["deflate-raw", "deflate-raw"], ["zlib", "gzip"]] {
+            let html = b"<html><head><title>hi</title></head><body>".repeat(50);
+            let mut payload = Payload::new(Box::new([
+                Segment::Block(Block::new(html.into_boxed_slice())),
+                Segment::Bomb(Bomb::new(Box::new(*b"ab"))),
+                Segment::Block(Block::new(vec![0x42; 70000].into_boxed_slice())),
+                Segment::Bomb(Bomb::new(Box::new([0x61]))),
+                Segment::Bomb(Bomb::new(Box::new(*b"xyz"))),
+            ]));
+            for layer in layers.iter().rev() {
+                payload = match *layer {
+                    "gzip" => gzip(payload),
+                    "zlib" => zlib(payload),
+                    _ => deflate_raw(payload),
+                };
+            }
+            payload.fill(&BigUint::from(10u8));
+
+            let mut data = Vec::<u8>::new();
+            payload.write(&mut data).unwrap();
+            for layer in layers {
+                data = real_inflate(layer, &data);
+            }
+            let mut expected = Vec::<u8>::new();
+            payload.child().unwrap().child().unwrap().write(&mut expected).unwrap();
+            assert_eq!(data, expected);
+            assert_eq!(BigUint::from(data.len()), payload.final_size());
+        }
+    }
+
+    /* The members decode one after another to the child, each with the right trailer */
+    #[test]
+    fn test_gzip_members() {
+        let options = GzipOptions {
+            members: 4,
+            ..GzipOptions::default()
+        };
+        let mut payload = gzip_with(Payload::new(Box::new([
+            Segment::Block(Block::new(Box::new(*b"<p>"))),
+            Segment::Bomb(Bomb::new(Box::new(*b"abc"))),
+            Segment::Bomb(Bomb::new(Box::new([0x61]))),
+            Segment::Block(Block::new(Box::new(*b"</p>"))),
+        ])), &options);
+        payload.fill(&BigUint::from(10u8));
+
+        let mut data = Vec::<u8>::new();
+        payload.write(&mut data).unwrap();
+        let mut expected = Vec::<u8>::new();
+        payload.child().unwrap().write(&mut expected).unwrap();
+
+        let mut content = Vec::<u8>::new();
+        let mut rest = &data[..];
+        let mut members = 0;
+        while !rest.is_empty() {
+            assert_eq!(&rest[..4], &[0x1f, 0x8b, 0x08, 0x00]);
+            let (member, len) = inflate(&rest[10..]).unwrap();
+            let trailer = &rest[10 + len..18 + len];
+            let mut crc = Crc32Engine::new();
+            crc.apply(&member);
+            assert_eq!(trailer[..4], *crc.bytes(ByteOrder::Little));
+            assert_eq!(trailer[4..], (member.len() as u32).to_le_bytes());
+            content.extend_from_slice(&member);
+            rest = &rest[18 + len..];
+            members += 1;
+        }
+        assert_eq!(members, 4);
+        assert_eq!(content, expected);
+    }
+
+    /* members divide the bomb size between them rather than each getting all of it */
+    #[test]
+    fn test_member_parts() {
+        let size = BigUint::from(1000u32);
+        let build = |members: usize| -> Payload {
+            let options = GzipOptions {
+                members: members,
+                ..GzipOptions::default()
+            };
+            let mut payload = gzip(gzip_with(Payload::new(Box::new([
+                Segment::Block(Block::new(Box::new(*b"<p>"))),
+                Segment::Bomb(Bomb::new(Box::new([0x61]))),
+            ])), &options));
+            payload.fill(&size);
+            return payload;
+        };
+
+        let one = build(1).final_size();
+        for members in [2, 3, 4, 7] {
+            let payload = build(members);
+
+            /* the outermost bombs share the size, one part per member */
+            let bombs: Vec<&Bomb> = payload.data.iter().filter_map(|s| match s {
+                Segment::Bomb(b) => Option::Some(b),
+                Segment::Block(_b) => Option::None,
+            }).collect();
+            assert_eq!(bombs.len(), members);
+            assert_eq!(bombs.iter().map(|b| b.size.clone()).sum::<BigUint>(), size);
+
+            /* so the content only grows by what each member's bombs add on their own */
+            let layer = payload.child().unwrap();
+            let sizes = layer.member_sizes();
+            assert_eq!(sizes.len(), members);
+            assert_eq!(sizes.iter().sum::<BigUint>(), layer.child().unwrap().size());
+            let total = payload.final_size();
+            assert!(total > one);
+            assert!(total - &one < BigUint::from(members * 2 * 1032 * 1032));
+            let (lo, hi) = (sizes.iter().min().unwrap(), sizes.iter().max().unwrap());
+            assert!(hi - lo <= BigUint::from(2 * 1032 * 1032u32));
+        }
+
+        /* only gzip layers have members */
+        assert!(build(2).member_sizes().len() == 1);
+        assert!(deflate_raw(Payload::new(Box::new([]))).member_sizes().is_empty());
+    }
+
+    #[test]
+    fn test_isize() {
+        assert_eq!(isize_bytes(&BigUint::ZERO), [0, 0, 0, 0]);
+        assert_eq!(isize_bytes(&BigUint::from(0x01020304u32)), [4, 3, 2, 1]);
+        assert_eq!(isize_bytes(&BigUint::from(1u64 << 32)), [0, 0, 0, 0]);
+        let big = BigUint::from(7u8).pow(100) + 5u8;
+        assert_eq!(isize_bytes(&big), ((&big % (1u64 << 32)).to_u32_digits()[0]).to_le_bytes());
+
+        /* an empty child is an empty stream */
+        let mut payload = gzip(Payload::new(Box::new([])));
+        payload.fill(&BigUint::from(10u8));
+        let mut data = Vec::<u8>::new();
+        payload.write(&mut data).unwrap();
+        assert_eq!(inflate(&data[10..]), Option::Some((vec![], 2)));
+        assert_eq!(&data[12..], &[0; 8]);
+
+        /* the last member has only what small_isize asks for, and the tail after it */
+        for pattern in [&b"a"[..], b"abc"] {
+            let options = GzipOptions {
+                small_isize: Option::Some(10),
+                ..GzipOptions::default()
+            };
+            let mut payload = gzip_with(Payload::new(Box::new([
+                Segment::Bomb(Bomb::new(pattern.into())),
+                Segment::Block(Block::new(Box::new(*b"</p>"))),
+            ])), &options);
+            payload.fill(&BigUint::from(10u8));
+            let mut data = Vec::<u8>::new();
+            payload.write(&mut data).unwrap();
+            let mut expected = Vec::<u8>::new();
+            payload.child().unwrap().write(&mut expected).unwrap();
+
+            let (first, len) = inflate(&data[10..]).unwrap();
+            let (last, _len) = inflate(&data[28 + len..]).unwrap();
+            assert_eq!([first, last.clone()].concat(), expected);
+            assert_eq!(last.len(), 14);
+            assert_eq!(&data[data.len() - 4..], &[14, 0, 0, 0]);
+        }
+    }
+
+    /* rather than quietly leaving the ISIZE as it is */
+    #[test]
+    #[should_panic(expected = "small_isize needs a bomb")]
+    fn test_small_isize_without_bomb() {
+        let options = GzipOptions {
+            small_isize: Option::Some(10),
+            ..GzipOptions::default()
+        };
+        gzip_with(Payload::new(Box::new([
+            Segment::Block(Block::new(Box::new(*b"<p></p>"))),
+        ])), &options);
+    }
+
+    #[test]
+    fn test_checksums() {
+        let mut payload = Payload::new(Box::new([
+            Segment::Block(Block::new(Box::new(*b"<p>"))),
+            Segment::Bomb(Bomb::new(Box::new([0x61, 0x62]))),
+            Segment::Block(Block::new(Box::new(*b"<p>"))),
+            Segment::Bomb(Bomb::new(Box::new([0x61, 0x62]))),
+        ]));
+        payload.fill(&BigUint::from(1001u16));
+
+        let mut data = Vec::<u8>::new();
+        payload.write(&mut data).unwrap();
+        let mut crc = Crc32Engine::new();
+        let mut adler = AdlerEngine::new();
+        crc.apply(&data);
+        adler.apply(&data);
+        assert_eq!(payload.crc32(), *crc.bytes(ByteOrder::Big));
+        assert_eq!(payload.adler32(), *adler.bytes(ByteOrder::Big));
+        assert_eq!(payload.checksum(Crc32Engine::new(), ByteOrder::Little), crc.bytes(ByteOrder::Little));
+
+        /* wider and narrower engines go through the same walker */
+        let mut crc64 = CrcEngine::new(&CRC_64_XZ);
+        let mut crc16 = CrcEngine::new(&CRC_16);
+        crc64.apply(&data);
+        crc16.apply(&data);
+        assert_eq!(payload.checksum(CrcEngine::new(&CRC_64_XZ), ByteOrder::Little),
+                crc64.value().to_le_bytes().into());
+        assert_eq!(payload.checksum(CrcEngine::new(&CRC_16), ByteOrder::Big),
+                crc16.value().to_be_bytes().into());
+
+        /* xxHash can't combine, so it's fed everything in order */
+        let mut xxh = Xxh64Engine::new(0);
+        xxh.apply(&data);
+        assert_eq!(payload.checksum(Xxh64Engine::new(0), ByteOrder::Little), xxh.bytes(ByteOrder::Little));
+
+        let mut huge = Payload::new(Box::new([Segment::Bomb(Bomb::new(Box::new([0x55])))]));
+        huge.fill(&BigUint::from(10u8).pow(100));
+        assert!(huge.try_checksum(Xxh64Engine::new(0), ByteOrder::Little).is_none());
+        assert!(huge.try_checksum(Crc32Engine::new(), ByteOrder::Little).is_some());
+    }
+
+    /* cargo test --release bench_googol -- --ignored --nocapture */
+    #[test]
+    #[ignore]
+    fn bench_googol() {
+        for layer in [gzip, zlib] {
+            let runs = 20;
+            let start = std::time::Instant::now();
+            for _i in 0..runs {
+                let mut payload = Payload::new(Box::new([
+                    Segment::Bomb(Bomb::new(Box::new([0x41]))),
+                ]));
+                for _j in 0..34 {
+                    payload = layer(payload);
+                }
+                payload.fill(&BigUint::from(1u8));
+            }
+            println!("34 layers: {:?}", start.elapsed() / runs);
+        }
+    }
+}
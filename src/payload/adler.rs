@@ -1,20 +1,17 @@
 use crate::payload::checksum::ChecksumEngine;
 use num::BigUint;
 
+#[derive(Clone)]
 pub struct AdlerEngine {
     s1: u32,
     s2: u32,
 }
 
-fn biguint_to_u32(n: BigUint) -> u32 {
-    let digits = n.to_u32_digits();
-    if digits.len() == 0 {
-        return 0;
-    }
-    if digits.len() != 1 {
-        panic!("Trying to convert large BigUint to u32");
-    }
-    return digits[0];
+const BASE: u64 = 65521;
+
+/* n mod 65521, for n of any size */
+fn mod_base(n: &BigUint) -> u64 {
+    return (n % BASE).to_u64_digits().first().copied().unwrap_or(0);
 }
 
 impl AdlerEngine {
@@ -26,56 +23,77 @@ impl AdlerEngine {
     }
 }
 
+impl Default for AdlerEngine {
+    fn default() -> AdlerEngine {
+        return AdlerEngine::new();
+    }
+}
+
 impl ChecksumEngine for AdlerEngine {
+    const WIDTH: usize = 4;
+
     fn apply1(&mut self, data: u8) {
         self.s1 += data as u32;
-        self.s1 %= 65521;
+        self.s1 %= BASE as u32;
         self.s2 += self.s1;
-        self.s2 %= 65521;
+        self.s2 %= BASE as u32;
     }
 
     fn apply_rep(&mut self, data: &[u8], reps: BigUint) {
-        /* See https://natechoe.dev/blog/2025-08-04.html */
-        let mut t1: u32 = 0;
-        let mut t2: u32 = 0;
+        /* See https://natechoe.dev/blog/2025-08-04.html
+         *
+         * One pass over the pattern from (s1, s2) gives (s1 + t1, s2 + len s1 + t2), where t1 is
+         * the sum of the pattern's bytes and t2 is the sum of its prefix sums. The k-th repetition
+         * starts with s1 + k t1, so after n of them
+         *
+         *   s1' = s1 + n t1
+         *   s2' = s2 + n len s1 + n t2 + len t1 n(n-1)/2
+         *
+         * Everything is reduced mod 65521 in u64s, so no product can overflow however long the
+         * pattern or large the count.
+         *
+         * Unlike CRCs, there's nothing here worth caching between calls: t1 and t2 take one pass
+         * over the pattern, the same as looking it up would. */
+        let mut t1: u64 = 0;
+        let mut t2: u64 = 0;
         for byte in data {
-            t1 += *byte as u32;
-            t1 %= 65521;
-            t2 += t1;
-            t2 += 65521;
+            t1 = (t1 + *byte as u64) % BASE;
+            t2 = (t2 + t1) % BASE;
         }
 
-        let tri = t2;
-        let rect = t1 * ((data.len() % 65521) as u32);
+        let len = data.len() as u64 % BASE;
+        let n = mod_base(&reps);
+        /* n(n-1)/2, using 32761 = 1/2 (mod 65521) since n(n-1) might not be reduced evenly */
+        let pairs = n * ((n + BASE - 1) % BASE) % BASE * 32761 % BASE;
 
-        let full_blocks = biguint_to_u32(reps % 65521u16);
-        let len = full_blocks * ((data.len() % 65521) as u32) % 65521;
+        let s1 = self.s1 as u64;
+        let s2 = self.s2 as u64;
+        let new_s1 = (s1 + n * t1) % BASE;
+        let new_s2 = (s2 + n * len % BASE * s1 + n * t2 + len * t1 % BASE * pairs) % BASE;
 
-        let num_rects_x2 = full_blocks * (full_blocks-1) % 65521;
-        let num_rects = num_rects_x2 * 32761 % 65521; // 32761 = 1/2 (mod 65521)
-
-        self.s2 += (self.s1 * len) % 65521;
-        self.s1 += (t1 * full_blocks) % 65521;
-        self.s2 += tri * full_blocks % 65521;
-        self.s2 += rect * num_rects % 65521;
+        self.s1 = new_s1 as u32;
+        self.s2 = new_s2 as u32;
+    }
 
-        self.s1 %= 65521;
-        self.s2 %= 65521;
+    fn combine(&mut self, b: &AdlerEngine, len_b: &BigUint) {
+        /* b started at s1 = 1, s2 = 0, so b.s1 - 1 is the sum of its bytes and b.s2 - len_b is the
+         * sum of its prefix sums. Starting from our s1 instead adds len_b * (s1 - 1) to s2. */
+        let len = mod_base(len_b);
+        let s1 = self.s1 as u64;
+        let s2 = self.s2 as u64;
+        self.s2 = ((s2 + b.s2 as u64 + len * ((s1 + BASE - 1) % BASE)) % BASE) as u32;
+        self.s1 = ((s1 + b.s1 as u64 + BASE - 1) % BASE) as u32;
     }
 
-    fn bytes(&self) -> [u8; 4] {
-        return [
-            (self.s2 >> 8) as u8,
-            (self.s2 & 255) as u8,
-            (self.s1 >> 8) as u8,
-            (self.s1 & 255) as u8,
-        ];
+    fn value(&self) -> u64 {
+        return ((self.s2 as u64) << 16) | self.s1 as u64;
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::payload::checksum::ByteOrder;
 
     #[test]
     fn test_adler() {
@@ -84,6 +102,90 @@ mod tests {
         engine.apply(&[0x65, 0x73, 0x74, 0x20]);
         engine.apply_rep(&[0x61, 0x62, 0x63], BigUint::ZERO + 3u8);
         engine.apply1(0x64);
-        assert_eq!(engine.bytes(), [0x2e, 0x12, 0x05, 0xb7]);
+        assert_eq!(*engine.bytes(ByteOrder::Big), [0x2e, 0x12, 0x05, 0xb7]);
+    }
+
+    #[test]
+    fn test_adler_combine() {
+        let mut a = AdlerEngine::new();
+        let mut b = AdlerEngine::new();
+        a.apply(&[0x74, 0x65, 0x73]);
+        b.apply(&[0x74, 0x20]);
+        b.apply_rep(&[0x61, 0x62, 0x63], BigUint::ZERO + 3u8);
+        b.apply1(0x64);
+        a.combine(&b, &BigUint::from(12u8));
+        assert_eq!(*a.bytes(ByteOrder::Big), [0x2e, 0x12, 0x05, 0xb7]);
+
+        let big = vec![0xffu8; 200000];
+        let mut whole = AdlerEngine::new();
+        let mut a = AdlerEngine::new();
+        let mut b = AdlerEngine::new();
+        whole.apply(&big);
+        a.apply(&big[..70000]);
+        b.apply(&big[70000..]);
+        a.combine(&b, &BigUint::from(130000u32));
+        assert_eq!(a.bytes(ByteOrder::Big), whole.bytes(ByteOrder::Big));
+    }
+
+    fn xorshift(seed: &mut u64) -> u64 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        return *seed;
+    }
+
+    fn random_pattern(seed: &mut u64) -> Vec<u8> {
+        let len = match xorshift(seed) % 4 {
+            0 => xorshift(seed) % 8,
+            1 => xorshift(seed) % 300,
+            2 => 65521 + xorshift(seed) % 300,
+            _ => xorshift(seed) % 100000,
+        };
+        /* mostly large bytes, which overflowed the old implementation soonest */
+        let high = xorshift(seed) & 1 == 0;
+        return (0..len).map(|_i| {
+            let b = xorshift(seed) as u8;
+            if high { b | 0xf0 } else { b }
+        }).collect();
+    }
+
+    #[test]
+    fn test_adler_rep_naive() {
+        let mut seed: u64 = 0x2545f4914f6cdd1d;
+        for _i in 0..50 {
+            let data = random_pattern(&mut seed);
+            let reps = xorshift(&mut seed) % 5;
+            let prefix = random_pattern(&mut seed);
+
+            let mut fast = AdlerEngine::new();
+            let mut slow = AdlerEngine::new();
+            fast.apply(&prefix);
+            slow.apply(&prefix);
+            fast.apply_rep(&data, BigUint::from(reps));
+            for _j in 0..reps {
+                slow.apply(&data);
+            }
+            assert_eq!(fast.bytes(ByteOrder::Big), slow.bytes(ByteOrder::Big));
+        }
+    }
+
+    /* Counts too large to check directly: repeating a + b times is repeating a times, then b. */
+    #[test]
+    fn test_adler_rep_large() {
+        let mut seed: u64 = 0x9e3779b97f4a7c15;
+        for _i in 0..200 {
+            let data = random_pattern(&mut seed);
+            let a = BigUint::from(xorshift(&mut seed)).pow((xorshift(&mut seed) % 6) as u32);
+            let b = BigUint::from(xorshift(&mut seed)) * 65521u32 + xorshift(&mut seed) % 3;
+
+            let mut once = AdlerEngine::new();
+            let mut twice = AdlerEngine::new();
+            once.apply1(0xff);
+            twice.apply1(0xff);
+            once.apply_rep(&data, &a + &b);
+            twice.apply_rep(&data, a);
+            twice.apply_rep(&data, b);
+            assert_eq!(once.bytes(ByteOrder::Big), twice.bytes(ByteOrder::Big));
+        }
     }
 }
@@ -1,6 +1,17 @@
 use num::BigUint;
 
+pub enum ByteOrder {
+    Big,
+    Little,
+}
+
 pub trait ChecksumEngine {
+    /* How many bytes the checksum takes up, at most 8 */
+    const WIDTH: usize;
+
+    /* Whether combine() works. Engines that can't combine are fed every segment in order. */
+    const COMBINES: bool = true;
+
     fn apply1(&mut self, data: u8);
 
     fn apply(&mut self, data: &[u8]) {
@@ -11,5 +22,26 @@ pub trait ChecksumEngine {
 
     fn apply_rep(&mut self, data: &[u8], reps: BigUint);
 
-    fn bytes(&self) -> [u8; 4];
+    /* Appends another checksum to this one, as if its data had been applied here. b must have
+     * started out as a new engine, and len_b is how many bytes it was given. */
+    fn combine(&mut self, b: &Self, len_b: &BigUint) where Self: Sized;
+
+    /* False if some data couldn't be hashed, e.g. a repetition too long for an engine without a
+     * shortcut for it. The value is meaningless then. */
+    fn valid(&self) -> bool {
+        return true;
+    }
+
+    /* The checksum as an integer, in the low WIDTH bytes */
+    fn value(&self) -> u64;
+
+    /* The checksum the way a container stores it, e.g. big endian for zlib's Adler-32 and little
+     * endian for gzip's CRC-32. */
+    fn bytes(&self, order: ByteOrder) -> Box<[u8]> {
+        let mut ret = self.value().to_be_bytes()[8 - Self::WIDTH..].to_vec();
+        if let ByteOrder::Little = order {
+            ret.reverse();
+        }
+        return ret.into_boxed_slice();
+    }
 }
@@ -0,0 +1,82 @@
+/* Deliberately wrong trailer values, for checking that decoders (ours included) reject a stream
+ * instead of trusting it. */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Corruption {
+    /* replace the value outright */
+    Fixed(u32),
+    /* flip one bit of it, 0 being the least significant */
+    FlipBit(u32),
+    /* add to it mod 2^32, e.g. 1 or -1 for an off by one size */
+    Offset(i64),
+}
+
+impl Corruption {
+    /* Parses "0x1234abcd" or "305441741" (fixed), "flip" or "flip:N" (flip bit N), "+N" or "-N"
+     * (offset) */
+    pub fn parse(text: &str) -> Option<Corruption> {
+        if let Option::Some(bit) = text.strip_prefix("flip") {
+            let bit = match bit.strip_prefix(':') {
+                Option::Some(n) => n.parse::<u32>().ok()?,
+                Option::None if bit.is_empty() => 0,
+                Option::None => return Option::None,
+            };
+            if bit >= 32 {
+                return Option::None;
+            }
+            return Option::Some(Corruption::FlipBit(bit));
+        }
+        if text.starts_with('+') || text.starts_with('-') {
+            return Option::Some(Corruption::Offset(text.parse::<i64>().ok()?));
+        }
+        if let Option::Some(hex) = text.strip_prefix("0x") {
+            return Option::Some(Corruption::Fixed(u32::from_str_radix(hex, 16).ok()?));
+        }
+        return Option::Some(Corruption::Fixed(text.parse::<u32>().ok()?));
+    }
+
+    pub fn apply(&self, v: u32) -> u32 {
+        return match self {
+            Corruption::Fixed(n) => *n,
+            Corruption::FlipBit(bit) => v ^ (1 << bit),
+            Corruption::Offset(n) => v.wrapping_add(*n as u32),
+        };
+    }
+}
+
+/* Applies an optional corruption to a 4 byte trailer field */
+pub fn corrupt(bytes: Box<[u8]>, corruption: Option<Corruption>, little_endian: bool) -> Box<[u8]> {
+    let c = match corruption {
+        Option::Some(c) => c,
+        Option::None => return bytes,
+    };
+    let arr: [u8; 4] = (*bytes).try_into().expect("Corrupted field isn't 4 bytes");
+    if little_endian {
+        return Box::new(c.apply(u32::from_le_bytes(arr)).to_le_bytes());
+    }
+    return Box::new(c.apply(u32::from_be_bytes(arr)).to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Corruption::parse("0xdeadbeef"), Option::Some(Corruption::Fixed(0xdeadbeef)));
+        assert_eq!(Corruption::parse("12"), Option::Some(Corruption::Fixed(12)));
+        assert_eq!(Corruption::parse("flip"), Option::Some(Corruption::FlipBit(0)));
+        assert_eq!(Corruption::parse("flip:31"), Option::Some(Corruption::FlipBit(31)));
+        assert_eq!(Corruption::parse("+1"), Option::Some(Corruption::Offset(1)));
+        assert_eq!(Corruption::parse("-1"), Option::Some(Corruption::Offset(-1)));
+        assert_eq!(Corruption::parse("flip:32"), Option::None);
+        assert_eq!(Corruption::parse("flipper"), Option::None);
+        assert_eq!(Corruption::parse("abc"), Option::None);
+
+        assert_eq!(Corruption::Offset(-1).apply(0), 0xffffffff);
+        assert_eq!(Corruption::FlipBit(4).apply(0x11), 0x01);
+        assert_eq!(*corrupt(Box::new([1, 0, 0, 0]), Option::Some(Corruption::Offset(1)), true),
+                [2, 0, 0, 0]);
+        assert_eq!(*corrupt(Box::new([0, 0, 0, 1]), Option::Some(Corruption::Offset(1)), false),
+                [0, 0, 0, 2]);
+    }
+}
@@ -0,0 +1,397 @@
+use crate::payload::checksum::ChecksumEngine;
+use num::BigUint;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+/* A CRC of any width up to 64 bits, described the usual way (the "Rocksoft model", the same
+ * parameters as the CRC RevEng catalogue).
+ *
+ * Note that the gzip header CRC (FHCRC) isn't a CRC-16 algorithm of its own, it's the low two bytes
+ * of the CRC-32 of the header, so it uses CRC_32. */
+pub struct CrcParams<W> {
+    pub width: u32,
+    pub poly: W,
+    pub init: W,
+    pub refin: bool,
+    pub refout: bool,
+    pub xorout: W,
+}
+
+/* gzip, zlib's crc32(), PNG, ... */
+pub const CRC_32: CrcParams<u32> = CrcParams {
+    width: 32,
+    poly: 0x04c11db7,
+    init: 0xffffffff,
+    refin: true,
+    refout: true,
+    xorout: 0xffffffff,
+};
+
+/* Castagnoli, used by iSCSI, ext4, and SSE4.2's crc32 instruction */
+pub const CRC_32C: CrcParams<u32> = CrcParams {
+    width: 32,
+    poly: 0x1edc6f41,
+    init: 0xffffffff,
+    refin: true,
+    refout: true,
+    xorout: 0xffffffff,
+};
+
+/* bzip2 */
+pub const CRC_32_BZIP2: CrcParams<u32> = CrcParams {
+    width: 32,
+    poly: 0x04c11db7,
+    init: 0xffffffff,
+    refin: false,
+    refout: false,
+    xorout: 0xffffffff,
+};
+
+/* xz */
+pub const CRC_64_XZ: CrcParams<u64> = CrcParams {
+    width: 64,
+    poly: 0x42f0e1eba9ea3693,
+    init: 0xffffffffffffffff,
+    refin: true,
+    refout: true,
+    xorout: 0xffffffffffffffff,
+};
+
+/* CRC-16/ARC, the "plain" CRC-16 */
+pub const CRC_16: CrcParams<u16> = CrcParams {
+    width: 16,
+    poly: 0x8005,
+    init: 0x0000,
+    refin: true,
+    refout: true,
+    xorout: 0x0000,
+};
+
+/* The unsigned integers a CRC can be stored in */
+pub trait CrcWord: Copy {
+    fn to_u64(self) -> u64;
+    fn from_u64(v: u64) -> Self;
+}
+
+macro_rules! crc_word {
+    ($t:ty) => {
+        impl CrcWord for $t {
+            fn to_u64(self) -> u64 {
+                return self as u64;
+            }
+
+            fn from_u64(v: u64) -> $t {
+                return v as $t;
+            }
+        }
+    };
+}
+
+crc_word!(u8);
+crc_word!(u16);
+crc_word!(u32);
+crc_word!(u64);
+
+fn reflect(v: u64, width: u32) -> u64 {
+    return v.reverse_bits() >> (64 - width);
+}
+
+fn mask(width: u32) -> u64 {
+    return u64::MAX >> (64 - width);
+}
+
+/* (width, poly, reflected, pattern length) */
+type DoublingKey = (u32, u64, bool, usize);
+
+thread_local! {
+    static DOUBLINGS: RefCell<HashMap<DoublingKey, Vec<(u64, u64)>>> = RefCell::new(HashMap::new());
+}
+
+/* Polynomials modulo a CRC polynomial, stored the same way a register for that CRC is stored:
+ * coefficient of x^i in bit i, or if reflected, in bit width-1-i.
+ *
+ * Processing a string of n bytes multiplies the register by x^(8n) and adds the CRC of the string
+ * from a zero register, so repeating a string is a geometric series, the same trick zlib's
+ * crc32_combine() uses. */
+#[derive(Clone)]
+pub struct CrcPoly {
+    width: u32,
+    /* the polynomial without its x^width term, in register order */
+    poly: u64,
+    reflected: bool,
+}
+
+impl CrcPoly {
+    pub const fn new(width: u32, poly: u64, reflected: bool) -> CrcPoly {
+        return CrcPoly {
+            width: width,
+            poly: poly,
+            reflected: reflected,
+        };
+    }
+
+    fn one(&self) -> u64 {
+        return if self.reflected { 1 << (self.width - 1) } else { 1 };
+    }
+
+    fn times_x(&self, a: u64) -> u64 {
+        if self.reflected {
+            return if a & 1 != 0 { (a >> 1) ^ self.poly } else { a >> 1 };
+        }
+        let top = (a >> (self.width - 1)) & 1;
+        let shifted = (a << 1) & mask(self.width);
+        return if top != 0 { shifted ^ self.poly } else { shifted };
+    }
+
+    /* a * b mod P, by Horner's rule over the coefficients of a, highest first */
+    pub fn mul(&self, a: u64, b: u64) -> u64 {
+        let mut ret: u64 = 0;
+        for i in 0..self.width {
+            ret = self.times_x(ret);
+            let bit = if self.reflected { i } else { self.width - 1 - i };
+            if (a >> bit) & 1 != 0 {
+                ret ^= b;
+            }
+        }
+        return ret;
+    }
+
+    /* X^(2^i) and 1 + X + ... + X^(2^i - 1) for X = x^(8 len) and every i below bits. These only
+     * depend on the polynomial and the pattern's length, so they're kept around for every engine
+     * on this thread to share: a layer's bombs all repeat the same byte, and so do the layers
+     * around it. */
+    fn doublings(&self, len: usize, bits: u64) -> Vec<(u64, u64)> {
+        let key = (self.width, self.poly, self.reflected, len);
+        let mut table = DOUBLINGS.with(|d| d.borrow().get(&key).cloned()).unwrap_or_default();
+        if table.len() as u64 >= bits {
+            table.truncate(bits as usize);
+            return table;
+        }
+
+        if table.is_empty() {
+            let x = if len == 1 {
+                (0..8).fold(self.one(), |x, _i| self.times_x(x))
+            } else {
+                self.x8n(&BigUint::from(len))
+            };
+            table.push((x, self.one()));
+        }
+        while (table.len() as u64) < bits {
+            /* 1 + ... + X^(2m-1) = (1 + ... + X^(m-1)) (1 + X^m) */
+            let (power, sum) = table[table.len() - 1];
+            table.push((self.mul(power, power), self.mul(sum, power ^ self.one())));
+        }
+
+        DOUBLINGS.with(|d| d.borrow_mut().insert(key, table.clone()));
+        return table;
+    }
+
+    /* x^(8n) mod P */
+    pub fn x8n(&self, n: &BigUint) -> u64 {
+        let mut ret = self.one();
+        for (i, (power, _sum)) in self.doublings(1, n.bits()).iter().enumerate() {
+            if n.bit(i as u64) {
+                ret = self.mul(ret, *power);
+            }
+        }
+        return ret;
+    }
+
+    /* The register after a string of len bytes is processed reps times starting from v, where
+     * single is the register after processing it once starting from zero.
+     *
+     * With X = x^(8 len), that's v X^reps + single (1 + X + ... + X^(reps-1)). Both terms are
+     * built up from the doublings of X one bit of reps at a time, using
+     * 1 + ... + X^(a+b-1) = (1 + ... + X^(a-1)) + X^a (1 + ... + X^(b-1)). */
+    pub fn repeat(&self, v: u64, single: u64, len: usize, reps: &BigUint) -> u64 {
+        /* X^m and 1 + X + ... + X^(m-1), for m the bits of reps seen so far */
+        let mut power = self.one();
+        let mut sum: u64 = 0;
+        for (i, (p, s)) in self.doublings(len, reps.bits()).iter().enumerate() {
+            if reps.bit(i as u64) {
+                sum ^= self.mul(power, *s);
+                power = self.mul(power, *p);
+            }
+        }
+        return self.mul(v, power) ^ self.mul(single, sum);
+    }
+
+    /* The register after processing A then B, given the register a after A, the register b after
+     * B, and the register both started from. b = init X + B and we want a X + B, with X the
+     * x^(8 len_b) from before. */
+    pub fn combine(&self, a: u64, b: u64, init: u64, len_b: &BigUint) -> u64 {
+        return self.mul(a ^ init, self.x8n(len_b)) ^ b;
+    }
+}
+
+/* The register is kept the way a table driven implementation would keep it: bit reversed if refin
+ * is set, so that bytes are always shifted in from the same end. */
+#[derive(Clone)]
+pub struct CrcEngine<W> {
+    v: u64,
+    init: u64,
+    poly: CrcPoly,
+    refout: bool,
+    xorout: u64,
+    word: PhantomData<W>,
+}
+
+impl<W: CrcWord> CrcEngine<W> {
+    pub fn new(params: &CrcParams<W>) -> CrcEngine<W> {
+        let width = params.width;
+        if width == 0 || width > 64 {
+            panic!("CRC width must be between 1 and 64 bits");
+        }
+        let poly = params.poly.to_u64() & mask(width);
+        let init = params.init.to_u64() & mask(width);
+
+        let init = if params.refin { reflect(init, width) } else { init };
+
+        return CrcEngine {
+            v: init,
+            init: init,
+            poly: CrcPoly::new(width, if params.refin { reflect(poly, width) } else { poly },
+                    params.refin),
+            refout: params.refout,
+            xorout: params.xorout.to_u64() & mask(width),
+            word: PhantomData,
+        };
+    }
+
+    fn step(&self, v: u64, byte: u8) -> u64 {
+        let mut v = v;
+        for i in 0..8 {
+            let bit = if self.poly.reflected { (byte >> i) & 1 } else { (byte >> (7 - i)) & 1 };
+            /* times_x feeds back the bit shifted out, the message bit is fed back on top of it */
+            v = self.poly.times_x(v);
+            if bit != 0 {
+                v ^= self.poly.poly;
+            }
+        }
+        return v;
+    }
+
+    pub fn apply1(&mut self, data: u8) {
+        self.v = self.step(self.v, data);
+    }
+
+    pub fn apply(&mut self, data: &[u8]) {
+        for byte in data {
+            self.apply1(*byte);
+        }
+    }
+
+    pub fn apply_rep(&mut self, data: &[u8], reps: &BigUint) {
+        let single = data.iter().fold(0, |v, byte| self.step(v, *byte));
+        self.v = self.poly.repeat(self.v, single, data.len(), reps);
+    }
+
+    pub fn combine(&mut self, b: &CrcEngine<W>, len_b: &BigUint) {
+        self.v = self.poly.combine(self.v, b.v, self.init, len_b);
+    }
+
+    pub fn value(&self) -> W {
+        let width = self.poly.width;
+        let v = if self.poly.reflected != self.refout { reflect(self.v, width) } else { self.v };
+        return W::from_u64(v ^ self.xorout);
+    }
+}
+
+impl<W: CrcWord> ChecksumEngine for CrcEngine<W> {
+    const WIDTH: usize = std::mem::size_of::<W>();
+
+    fn apply1(&mut self, data: u8) {
+        CrcEngine::apply1(self, data);
+    }
+
+    fn apply_rep(&mut self, data: &[u8], reps: BigUint) {
+        CrcEngine::apply_rep(self, data, &reps);
+    }
+
+    fn combine(&mut self, b: &CrcEngine<W>, len_b: &BigUint) {
+        CrcEngine::combine(self, b, len_b);
+    }
+
+    fn value(&self) -> u64 {
+        return CrcEngine::value(self).to_u64();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::checksum::ByteOrder;
+
+    fn check<W: CrcWord>(params: &CrcParams<W>, expected: u64) {
+        let mut engine = CrcEngine::new(params);
+        engine.apply(b"123456789");
+        assert_eq!(engine.value().to_u64(), expected);
+
+        /* repeating matches doing it by hand */
+        for reps in [0u32, 1, 2, 7, 100] {
+            let mut fast = CrcEngine::new(params);
+            let mut slow = CrcEngine::new(params);
+            fast.apply(b"x");
+            slow.apply(b"x");
+            fast.apply_rep(b"abc", &BigUint::from(reps));
+            for _i in 0..reps {
+                slow.apply(b"abc");
+            }
+            assert_eq!(fast.value().to_u64(), slow.value().to_u64());
+        }
+
+        /* so does combining */
+        let mut a = CrcEngine::new(params);
+        let mut b = CrcEngine::new(params);
+        a.apply(b"1234");
+        b.apply(b"56789");
+        a.combine(&b, &BigUint::from(5u8));
+        assert_eq!(a.value().to_u64(), expected);
+    }
+
+    #[test]
+    fn test_check_values() {
+        check(&CRC_32, 0xcbf43926);
+        check(&CRC_32C, 0xe3069283);
+        check(&CRC_32_BZIP2, 0xfc891918);
+        check(&CRC_64_XZ, 0x995dc9bbdf1939fa);
+        check(&CRC_16, 0xbb3d);
+    }
+
+    /* The doublings are shared, so they have to come out the same however they were built up */
+    #[test]
+    fn test_doublings() {
+        let poly = CrcPoly::new(64, 0xc96c5795d7870f42, true);
+        let googol = BigUint::from(10u8).pow(100);
+        let x8 = (0..8).fold(poly.one(), |x, _i| poly.times_x(x));
+
+        let mut expected = poly.one();
+        for i in (0..googol.bits()).rev() {
+            expected = poly.mul(expected, expected);
+            if googol.bit(i) {
+                expected = poly.mul(expected, x8);
+            }
+        }
+
+        poly.x8n(&BigUint::from(5u8));
+        assert_eq!(poly.x8n(&googol), expected);
+        assert_eq!(poly.x8n(&googol), expected);
+        assert_eq!(poly.repeat(0, poly.one(), 1, &BigUint::from(3u8)),
+                poly.one() ^ x8 ^ poly.mul(x8, x8));
+    }
+
+    #[test]
+    fn test_crc32_compat() {
+        let mut engine = CrcEngine::new(&CRC_32);
+        ChecksumEngine::apply1(&mut engine, 0x74);
+        ChecksumEngine::apply(&mut engine, &[0x65, 0x73, 0x74, 0x20]);
+        ChecksumEngine::apply_rep(&mut engine, &[0x61, 0x62, 0x63], BigUint::ZERO + 3u8);
+        ChecksumEngine::apply1(&mut engine, 0x64);
+        assert_eq!(*ChecksumEngine::bytes(&engine, ByteOrder::Big), [0x9d, 0x1e, 0xef, 0xde]);
+
+        /* googol repetitions still finish */
+        let googol = BigUint::from(10u8).pow(100);
+        engine.apply_rep(b"\x55", &googol);
+    }
+}
@@ -1,9 +1,17 @@
 use crate::payload::checksum::ChecksumEngine;
-use crate::payload::crc32::matrix::CrcMatrix;
+use crate::payload::crc::CrcPoly;
 use num::BigUint;
 
+mod table;
+mod simd;
+
+/* The old bit matrix implementation of apply_rep, kept to test and benchmark against */
+#[cfg(test)]
 mod matrix;
 
+const POLY: CrcPoly = CrcPoly::new(32, 0xedb88320, true);
+
+#[derive(Clone)]
 pub struct Crc32Engine {
     v: u32,
 }
@@ -16,20 +24,102 @@ impl Crc32Engine {
     }
 }
 
+impl Default for Crc32Engine {
+    fn default() -> Crc32Engine {
+        return Crc32Engine::new();
+    }
+}
+
 impl ChecksumEngine for Crc32Engine {
+    const WIDTH: usize = 4;
+
     fn apply1(&mut self, data: u8) {
-        self.v ^= data as u32;
-        for _i in 0..8 {
-            if (self.v & 1) != 0 {
-                self.v = (self.v >> 1) ^ 0xedb88320;
-            } else {
-                self.v = self.v >> 1;
+        self.v = table::update1(self.v, data);
+    }
+
+    fn apply(&mut self, data: &[u8]) {
+        self.v = simd::update(self.v, data);
+    }
+
+    fn apply_rep(&mut self, data: &[u8], reps: BigUint) {
+        let mut single = Crc32Engine {
+            v: 0,
+        };
+        single.apply(data);
+        self.v = POLY.repeat(self.v as u64, single.v as u64, data.len(), &reps) as u32;
+    }
+
+    fn combine(&mut self, b: &Crc32Engine, len_b: &BigUint) {
+        self.v = POLY.combine(self.v as u64, b.v as u64, 0xffffffff, len_b) as u32;
+    }
+
+    fn value(&self) -> u64 {
+        return !self.v as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::checksum::ByteOrder;
+    use crate::payload::crc::{CrcEngine, CRC_32};
+
+    #[test]
+    fn test_crc32() {
+        let mut engine = Crc32Engine::new();
+        engine.apply1(0x74);
+        engine.apply(&[0x65, 0x73, 0x74, 0x20]);
+        engine.apply_rep(&[0x61, 0x62, 0x63], BigUint::ZERO + 3u8);
+        engine.apply1(0x64);
+        assert_eq!(*engine.bytes(ByteOrder::Big), [0x9d, 0x1e, 0xef, 0xde]);
+    }
+
+    /* Compares every implementation against the plain bitwise CRC on random data of every length
+     * and alignment around the cutoffs. */
+    #[test]
+    fn test_fast_paths() {
+        let mut seed: u64 = 0x853c49e6748fea9b;
+        let data: Vec<u8> = (0..4096).map(|_i| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as u8
+        }).collect();
+
+        let mut lengths: Vec<usize> = (0..300).collect();
+        lengths.extend([1000, 4000]);
+        for offset in 0..8 {
+            for len in lengths.iter() {
+                let slice = &data[offset..offset + len];
+                let mut bitwise = CrcEngine::new(&CRC_32);
+                bitwise.apply(slice);
+                let expected = !bitwise.value();
+
+                assert_eq!(table::update(0xffffffff, slice), expected);
+                assert_eq!(simd::update(0xffffffff, slice), expected);
+                assert_eq!(slice.iter().fold(0xffffffff, |v, b| table::update1(v, *b)), expected);
             }
         }
     }
 
-    fn apply_rep(&mut self, data: &[u8], reps: BigUint) {
-        let mut matr = CrcMatrix::new();
+    #[test]
+    fn test_crc32_combine() {
+        let mut a = Crc32Engine::new();
+        let mut b = Crc32Engine::new();
+        a.apply(b"test ");
+        b.apply_rep(b"abc", BigUint::from(3u8));
+        b.apply1(0x64);
+        a.combine(&b, &BigUint::from(10u8));
+        assert_eq!(*a.bytes(ByteOrder::Big), [0x9d, 0x1e, 0xef, 0xde]);
+
+        let empty = Crc32Engine::new();
+        a.combine(&empty, &BigUint::ZERO);
+        assert_eq!(*a.bytes(ByteOrder::Big), [0x9d, 0x1e, 0xef, 0xde]);
+    }
+
+    /* apply_rep as it was before CrcPoly */
+    fn apply_rep_matrix(engine: &mut Crc32Engine, data: &[u8], reps: &BigUint) {
+        let mut matr = matrix::CrcMatrix::new();
 
         for i in 0..data.len() {
             let byte = data[data.len() - i - 1];
@@ -42,32 +132,74 @@ impl ChecksumEngine for Crc32Engine {
             }
         }
 
-        matr.exponentiate(&reps);
-        self.v = matr.apply(self.v);
+        matr.exponentiate(reps);
+        engine.v = matr.apply(engine.v);
     }
 
-    fn bytes(&self) -> [u8; 4] {
-        let crc = !self.v;
-        return [
-            (crc >> 24) as u8,
-            (crc >> 16) as u8,
-            (crc >> 8)  as u8,
-            (crc >> 0)  as u8,
-        ];
+    #[test]
+    fn test_matrix_agrees() {
+        let googol = BigUint::from(10u8).pow(100);
+        for (data, reps) in [
+            (&b"U"[..], googol.clone()),
+            (b"<div>", googol.clone() + 12345u16),
+            (b"abc", BigUint::from(1u8)),
+            (b"", BigUint::from(3u8)),
+        ] {
+            let mut poly = Crc32Engine::new();
+            let mut matr = Crc32Engine::new();
+            poly.apply(b"head");
+            matr.apply(b"head");
+            poly.apply_rep(data, reps.clone());
+            apply_rep_matrix(&mut matr, data, &reps);
+            assert_eq!(poly.bytes(ByteOrder::Big), matr.bytes(ByteOrder::Big));
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /* cargo test --release bench_apply_rep -- --ignored --nocapture */
+    #[test]
+    #[ignore]
+    fn bench_apply_rep() {
+        let googol = BigUint::from(10u8).pow(100);
+        let patterns: [&[u8]; 3] = [b"U", b"{\"a\":1},", &[0x55; 1024]];
 
+        for data in patterns {
+            let runs = 20;
+            let start = std::time::Instant::now();
+            for _i in 0..runs {
+                Crc32Engine::new().apply_rep(data, googol.clone());
+            }
+            let poly = start.elapsed() / runs;
+
+            let start = std::time::Instant::now();
+            for _i in 0..runs {
+                apply_rep_matrix(&mut Crc32Engine::new(), data, &googol);
+            }
+            let matr = start.elapsed() / runs;
+
+            println!("{:5} byte pattern x 10^100: polynomial {:?}, matrix {:?}", data.len(), poly,
+                    matr);
+        }
+    }
+
+    /* cargo test --release bench_apply -- --ignored --nocapture */
     #[test]
-    fn test_crc32() {
-        let mut engine = Crc32Engine::new();
-        engine.apply1(0x74);
-        engine.apply(&[0x65, 0x73, 0x74, 0x20]);
-        engine.apply_rep(&[0x61, 0x62, 0x63], BigUint::ZERO + 3u8);
-        engine.apply1(0x64);
-        assert_eq!(engine.bytes(), [0x9d, 0x1e, 0xef, 0xde]);
+    #[ignore]
+    fn bench_apply() {
+        let data = vec![0x55u8; 16 << 20];
+        let mb = (data.len() >> 20) as f64;
+
+        let start = std::time::Instant::now();
+        let mut bitwise = CrcEngine::new(&CRC_32);
+        bitwise.apply(&data);
+        let speed = mb / start.elapsed().as_secs_f64();
+        println!("bitwise: {:.0} MiB/s", speed);
+
+        let start = std::time::Instant::now();
+        let v = table::update(0xffffffff, &data);
+        println!("slicing-by-8: {:.0} MiB/s", mb / start.elapsed().as_secs_f64());
+
+        let start = std::time::Instant::now();
+        assert_eq!(simd::update(0xffffffff, &data), v);
+        println!("simd: {:.0} MiB/s", mb / start.elapsed().as_secs_f64());
     }
 }
@@ -0,0 +1,118 @@
+use crate::payload::crc32::table;
+
+/* Hardware CRC-32 for long inputs, picked at runtime. Everything works on the raw register, the
+ * same as table::update, which is used whenever the CPU can't help or the input is too short for
+ * it to matter. */
+pub fn update(v: u32, data: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if data.len() >= 128 && is_x86_feature_detected!("pclmulqdq")
+                && is_x86_feature_detected!("sse4.1") {
+            return unsafe { x86::update(v, data) };
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("crc") {
+            return unsafe { arm::update(v, data) };
+        }
+    }
+
+    return table::update(v, data);
+}
+
+/* Folding with carry-less multiplication, from Intel's "Fast CRC Computation for Generic
+ * Polynomials Using PCLMULQDQ Instruction". The constants are powers of x mod the bit reflected
+ * CRC-32 polynomial, the same ones zlib and Linux use. */
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use crate::payload::crc32::table;
+    use std::arch::x86_64::*;
+
+    /* x^(4*128+32) mod P and x^(4*128-32) mod P, to fold 512 bits at a time */
+    const K1: i64 = 0x154442bd4;
+    const K2: i64 = 0x1c6e41596;
+    /* the same for 128 bits at a time */
+    const K3: i64 = 0x1751997d0;
+    const K4: i64 = 0x0ccaa009e;
+    /* x^64 mod P */
+    const K5: i64 = 0x163cd6124;
+    /* P and floor(x^64 / P), for the final Barrett reduction */
+    const P_X: i64 = 0x1db710641;
+    const U_PRIME: i64 = 0x1f7011641;
+
+    #[target_feature(enable = "pclmulqdq,sse2,sse4.1")]
+    fn fold(a: __m128i, b: __m128i, keys: __m128i) -> __m128i {
+        let t1 = _mm_clmulepi64_si128(a, keys, 0x00);
+        let t2 = _mm_clmulepi64_si128(a, keys, 0x11);
+        return _mm_xor_si128(_mm_xor_si128(b, t1), t2);
+    }
+
+    #[target_feature(enable = "pclmulqdq,sse2,sse4.1")]
+    fn load(data: &mut &[u8]) -> __m128i {
+        let ret = unsafe { _mm_loadu_si128(data.as_ptr() as *const __m128i) };
+        *data = &data[16..];
+        return ret;
+    }
+
+    /* data must be at least 64 bytes long */
+    #[target_feature(enable = "pclmulqdq,sse2,sse4.1")]
+    pub fn update(v: u32, data: &[u8]) -> u32 {
+        let mut data = data;
+
+        let mut x3 = load(&mut data);
+        let mut x2 = load(&mut data);
+        let mut x1 = load(&mut data);
+        let mut x0 = load(&mut data);
+        x3 = _mm_xor_si128(x3, _mm_cvtsi32_si128(v as i32));
+
+        let k1k2 = _mm_set_epi64x(K2, K1);
+        while data.len() >= 64 {
+            x3 = fold(x3, load(&mut data), k1k2);
+            x2 = fold(x2, load(&mut data), k1k2);
+            x1 = fold(x1, load(&mut data), k1k2);
+            x0 = fold(x0, load(&mut data), k1k2);
+        }
+
+        let k3k4 = _mm_set_epi64x(K4, K3);
+        let mut x = fold(x3, x2, k3k4);
+        x = fold(x, x1, k3k4);
+        x = fold(x, x0, k3k4);
+        while data.len() >= 16 {
+            x = fold(x, load(&mut data), k3k4);
+        }
+
+        /* 128 bits down to 64 */
+        let low32 = _mm_set_epi32(0, 0, 0, !0);
+        let x = _mm_xor_si128(_mm_clmulepi64_si128(x, k3k4, 0x10), _mm_srli_si128(x, 8));
+        let x = _mm_xor_si128(
+                _mm_clmulepi64_si128(_mm_and_si128(x, low32), _mm_set_epi64x(0, K5), 0x00),
+                _mm_srli_si128(x, 4));
+
+        /* and Barrett reduction from 64 to 32 */
+        let pu = _mm_set_epi64x(U_PRIME, P_X);
+        let t1 = _mm_clmulepi64_si128(_mm_and_si128(x, low32), pu, 0x10);
+        let t2 = _mm_clmulepi64_si128(_mm_and_si128(t1, low32), pu, 0x00);
+        let ret = _mm_extract_epi32(_mm_xor_si128(x, t2), 1) as u32;
+
+        return table::update(ret, data);
+    }
+}
+
+/* ARMv8 has CRC-32 instructions for this exact polynomial, 8 bytes at a time. */
+#[cfg(target_arch = "aarch64")]
+mod arm {
+    use crate::payload::crc32::table;
+    use std::arch::aarch64::*;
+
+    #[target_feature(enable = "crc")]
+    pub fn update(v: u32, data: &[u8]) -> u32 {
+        let mut v = v;
+        let mut chunks = data.chunks_exact(8);
+        for c in &mut chunks {
+            v = __crc32d(v, u64::from_le_bytes(c.try_into().unwrap()));
+        }
+        return table::update(v, chunks.remainder());
+    }
+}
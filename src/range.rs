@@ -0,0 +1,274 @@
+use crate::payload::{delivered, write_all, Delivery, Payload};
+use num::BigUint;
+use std::io;
+use std::str::FromStr;
+
+/* Bombs only ever repeat their own pattern and the literal blocks come from the user, so a fixed
+ * boundary is good enough for multipart/byteranges responses. */
+const BOUNDARY: &str = "ied-byteranges-boundary";
+
+/* An inclusive range of bytes, the same way Content-Range counts them. */
+pub struct ByteRange {
+    pub first: BigUint,
+    pub last: BigUint,
+}
+
+pub enum RangeRequest {
+    /* No (usable) Range header, send the entire payload. */
+    Full,
+    /* Send each range, in the order the client asked for them. */
+    Partial(Vec<ByteRange>),
+    /* None of the requested ranges overlap the payload. */
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> BigUint {
+        return &self.last - &self.first + 1u8;
+    }
+
+    fn content_range(&self, size: &BigUint) -> String {
+        return format!("bytes {}-{}/{}", self.first, self.last, size);
+    }
+}
+
+fn parse_num(s: &str) -> Option<BigUint> {
+    if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit()) {
+        return Option::None;
+    }
+    return BigUint::from_str(s).ok();
+}
+
+/* Parses the value of a Range header against a payload of the given size (RFC 9110, section
+ * 14.1.2). A header we can't understand is ignored and the whole payload is sent, as the RFC
+ * allows. Sizes are BigUints since a payload can be far larger than any u64. */
+pub fn parse(header: &str, size: &BigUint) -> RangeRequest {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Option::Some(s) => s,
+        Option::None => return RangeRequest::Full,
+    };
+
+    let mut ranges = Vec::<ByteRange>::new();
+    let mut seen = false;
+    for raw in spec.split(',') {
+        let r = raw.trim();
+        if r.is_empty() {
+            continue;
+        }
+        seen = true;
+
+        let (first_raw, last_raw) = match r.split_once('-') {
+            Option::Some(bounds) => bounds,
+            Option::None => return RangeRequest::Full,
+        };
+
+        if first_raw.is_empty() {
+            /* suffix range, the last n bytes */
+            let n = match parse_num(last_raw) {
+                Option::Some(n) => n,
+                Option::None => return RangeRequest::Full,
+            };
+            if n == BigUint::ZERO || *size == BigUint::ZERO {
+                continue;
+            }
+            let first = if n >= *size { BigUint::ZERO } else { size - &n };
+            ranges.push(ByteRange {
+                first: first,
+                last: size - 1u8,
+            });
+            continue;
+        }
+
+        let first = match parse_num(first_raw) {
+            Option::Some(n) => n,
+            Option::None => return RangeRequest::Full,
+        };
+        let mut last = if last_raw.is_empty() {
+            Option::None
+        } else {
+            match parse_num(last_raw) {
+                Option::Some(n) if n >= first => Option::Some(n),
+                _ => return RangeRequest::Full,
+            }
+        };
+
+        if first >= *size {
+            continue;
+        }
+        let end = size - 1u8;
+        if last.as_ref().is_none_or(|l| *l > end) {
+            last = Option::Some(end);
+        }
+        ranges.push(ByteRange {
+            first: first,
+            last: last.unwrap(),
+        });
+    }
+
+    if !seen {
+        return RangeRequest::Full;
+    }
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+    return RangeRequest::Partial(ranges);
+}
+
+fn part_header(range: &ByteRange, size: &BigUint, headers: &[(&str, &str)]) -> String {
+    let mut ret = format!("\r\n--{}\r\n", BOUNDARY);
+    for (name, value) in headers {
+        ret += &format!("{}: {}\r\n", name, value);
+    }
+    ret += &format!("Content-Range: {}\r\n\r\n", range.content_range(size));
+    return ret;
+}
+
+/* Writes a complete HTTP/1.1 response for the given request. headers are the representation
+ * headers of the payload (Content-Type, Content-Encoding, ...), which go on the response itself or
+ * on each part of a multipart/byteranges body. The ranges are over the encoded payload, so the
+ * payload must already be filled. */
+pub fn write_response(output: &mut impl io::Write, payload: &Payload, request: &RangeRequest,
+                      headers: &[(&str, &str)]) -> io::Result<Delivery> {
+    let mut bytes: usize = 0;
+    let result = stream_response(output, payload, request, headers, &mut bytes);
+    return delivered(result, bytes);
+}
+
+fn stream_response(output: &mut impl io::Write, payload: &Payload, request: &RangeRequest,
+                   headers: &[(&str, &str)], bytes: &mut usize) -> io::Result<()> {
+    let size = payload.size();
+    let mut head = String::new();
+
+    match request {
+        RangeRequest::Full => {
+            head += "HTTP/1.1 200 OK\r\nAccept-Ranges: bytes\r\n";
+            for (name, value) in headers {
+                head += &format!("{}: {}\r\n", name, value);
+            }
+            head += &format!("Content-Length: {}\r\n\r\n", size);
+            write_all(output, head.as_bytes(), bytes)?;
+            return payload.stream_range(output, &BigUint::ZERO, &size, bytes);
+        }
+        RangeRequest::Unsatisfiable => {
+            head += &format!("HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\n\
+                    Content-Length: 0\r\n\r\n", size);
+            write_all(output, head.as_bytes(), bytes)?;
+            return output.flush();
+        }
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            let range = &ranges[0];
+            head += "HTTP/1.1 206 Partial Content\r\nAccept-Ranges: bytes\r\n";
+            for (name, value) in headers {
+                head += &format!("{}: {}\r\n", name, value);
+            }
+            head += &format!("Content-Range: {}\r\nContent-Length: {}\r\n\r\n",
+                    range.content_range(&size), range.len());
+            write_all(output, head.as_bytes(), bytes)?;
+            return payload.stream_range(output, &range.first, &range.len(), bytes);
+        }
+        RangeRequest::Partial(ranges) => {
+            let trailer = format!("\r\n--{}--\r\n", BOUNDARY);
+            let mut body_len = BigUint::from(trailer.len());
+            for range in ranges {
+                body_len += part_header(range, &size, headers).len();
+                body_len += range.len();
+            }
+
+            head += &format!("HTTP/1.1 206 Partial Content\r\nAccept-Ranges: bytes\r\n\
+                    Content-Type: multipart/byteranges; boundary={}\r\n\
+                    Content-Length: {}\r\n\r\n", BOUNDARY, body_len);
+            write_all(output, head.as_bytes(), bytes)?;
+            for range in ranges {
+                write_all(output, part_header(range, &size, headers).as_bytes(), bytes)?;
+                payload.stream_range(output, &range.first, &range.len(), bytes)?;
+            }
+            write_all(output, trailer.as_bytes(), bytes)?;
+            return output.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::*;
+
+    fn bomb_payload() -> Payload {
+        let mut payload = gzip(Payload::new(Box::new([
+            Segment::Block(Block::new(Box::new(*b"<html>"))),
+            Segment::Bomb(Bomb::new(Box::new([0x61]))),
+            Segment::Block(Block::new(Box::new(*b"</html>"))),
+        ])));
+        payload.fill(&BigUint::from(3u8));
+        return payload;
+    }
+
+    fn ranges(request: RangeRequest) -> Vec<(u32, u32)> {
+        if let RangeRequest::Partial(r) = request {
+            return r.iter().map(|r| (
+                    r.first.to_u32_digits().first().copied().unwrap_or(0),
+                    r.last.to_u32_digits().first().copied().unwrap_or(0))).collect();
+        }
+        panic!("Expected a partial request");
+    }
+
+    #[test]
+    fn test_parse() {
+        let size = BigUint::from(100u8);
+        assert_eq!(ranges(parse("bytes=10-", &size)), [(10, 99)]);
+        assert_eq!(ranges(parse("bytes=0-0, -5", &size)), [(0, 0), (95, 99)]);
+        assert_eq!(ranges(parse("bytes=90-1000,-1000", &size)), [(90, 99), (0, 99)]);
+        assert!(matches!(parse("bytes=100-", &size), RangeRequest::Unsatisfiable));
+        assert!(matches!(parse("bytes=5-4", &size), RangeRequest::Full));
+        assert!(matches!(parse("items=0-4", &size), RangeRequest::Full));
+        assert!(matches!(parse("bytes=", &size), RangeRequest::Full));
+    }
+
+    #[test]
+    fn test_write_range() {
+        let payload = bomb_payload();
+        let mut full = Vec::<u8>::new();
+        payload.write(&mut full).unwrap();
+
+        for first in 0..full.len() {
+            for len in 0..(full.len() - first + 2) {
+                let mut part = Vec::<u8>::new();
+                payload.write_range(&mut part, &BigUint::from(first), &BigUint::from(len)).unwrap();
+                let end = std::cmp::min(first + len, full.len());
+                assert_eq!(part, &full[first..end]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_multipart() {
+        let payload = bomb_payload();
+        let mut full = Vec::<u8>::new();
+        payload.write(&mut full).unwrap();
+
+        let request = parse("bytes=0-1,-4", &payload.size());
+        let mut response = Vec::<u8>::new();
+        let written = write_response(&mut response, &payload, &request,
+                &[("Content-Type", "text/html"), ("Content-Encoding", "gzip")]).unwrap();
+        assert_eq!(written.bytes, response.len());
+        assert!(!written.disconnected);
+
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&response[..split + 2]).into_owned();
+        let body = &response[split + 4..];
+        assert!(head.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+
+        let mut expected = Vec::<u8>::new();
+        expected.extend_from_slice(format!("\r\n--{}\r\nContent-Type: text/html\r\n\
+                Content-Encoding: gzip\r\nContent-Range: bytes 0-1/{}\r\n\r\n",
+                BOUNDARY, full.len()).as_bytes());
+        expected.extend_from_slice(&full[..2]);
+        expected.extend_from_slice(format!("\r\n--{}\r\nContent-Type: text/html\r\n\
+                Content-Encoding: gzip\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                BOUNDARY, full.len() - 4, full.len() - 1, full.len()).as_bytes());
+        expected.extend_from_slice(&full[full.len() - 4..]);
+        expected.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+        assert_eq!(body, expected);
+    }
+}
@@ -0,0 +1,636 @@
+use crate::payload::*;
+use crate::payload::corrupt::Corruption;
+use num::BigUint;
+use std::io::Read;
+use std::str::FromStr;
+
+#[derive(Clone)]
+pub enum Part {
+    /* -f, a file copied in verbatim */
+    File(String),
+    /* -l and -L, a bomb of one byte */
+    Byte(u8),
+    /* -p, a bomb of a repeated string */
+    Pattern(String),
+}
+
+/* Everything needed to build a payload: the content encodings, the bomb size and the contents of
+ * the innermost layer. This is what the command line describes. */
+#[derive(Clone)]
+pub struct Recipe {
+    pub encodings: Vec<String>,
+    pub size: BigUint,
+    pub parts: Vec<Part>,
+}
+
+/* The most gzip members member_size can ask for. Every member is a few dozen bytes of headers
+ * and trailers on its own, and gets a bomb in every layer above it. */
+const MAX_MEMBERS: usize = 1 << 12;
+
+/* Splits a list of encodings on the commas that aren't inside an encoding's options, so
+ * "gzip(crc32=flip,isize=+1), deflate" is two layers. */
+pub fn split_encodings(text: &str) -> Vec<String> {
+    let mut ret = Vec::<String>::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                ret.push(text[start..i].to_string());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    ret.push(text[start..].to_string());
+
+    return ret.iter()
+        .map(|m| m.trim())
+        .filter(|m| !m.is_empty())
+        .map(|m| m.to_string())
+        .collect();
+}
+
+/* Splits "name(key=value,...)" into its name and options */
+fn parse_encoding(spec: &str) -> (&str, Vec<(&str, &str)>) {
+    let (name, options) = match spec.split_once('(') {
+        Option::Some((name, rest)) => {
+            (name, rest.strip_suffix(')').expect("Encoding options missing a closing parenthesis"))
+        }
+        Option::None => (spec, ""),
+    };
+
+    let options = options.split(',')
+        .map(|o| o.trim())
+        .filter(|o| !o.is_empty())
+        .map(|o| o.split_once('=').unwrap_or_else(|| panic!("Encoding option {} has no value", o)))
+        .map(|(k, v)| (k.trim(), v.trim()))
+        .collect();
+    return (name.trim(), options);
+}
+
+/* A byte, in hex (0x41) or decimal */
+fn byte_value(key: &str, value: &str) -> u8 {
+    let byte = match value.strip_prefix("0x") {
+        Option::Some(hex) => u8::from_str_radix(hex, 16),
+        Option::None => value.parse::<u8>(),
+    };
+    return byte.unwrap_or_else(|_| panic!("Invalid {} {}", key, value));
+}
+
+/* "auto" or a byte */
+fn bomb_byte(value: &str) -> Option<u8> {
+    if value == "auto" {
+        return Option::None;
+    }
+    return Option::Some(byte_value("bomb byte", value));
+}
+
+fn yes_no(key: &str, value: &str) -> bool {
+    return match value {
+        "yes" => true,
+        "no" => false,
+        _ => panic!("Invalid {} {}, expected yes or no", key, value),
+    };
+}
+
+/* A gzip extra subfield, "AB:0102ff" being the ID "AB" and the data in hex */
+fn extra_subfield(value: &str) -> ([u8; 2], Vec<u8>) {
+    let invalid = || -> ! { panic!("Invalid extra subfield {}", value) };
+    let (id, hex) = value.split_once(':').unwrap_or_else(|| invalid());
+    let id: [u8; 2] = id.as_bytes().try_into().unwrap_or_else(|_| invalid());
+    if hex.len() % 2 != 0 {
+        invalid();
+    }
+    let data = (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()
+        .unwrap_or_else(|| invalid());
+    return (id, data);
+}
+
+/* "stored" or "huffman", how known data outside the bombs is written */
+fn stored_literals(value: &str) -> bool {
+    return match value {
+        "stored" => true,
+        "huffman" => false,
+        _ => panic!("Invalid literals {}", value),
+    };
+}
+
+/* A window size, as a power of two from 8 to 15 */
+fn window_bits(value: &str) -> u8 {
+    return match value.parse::<u8>() {
+        Ok(bits) if (8..=15).contains(&bits) => bits,
+        _ => panic!("Invalid window_bits {}", value),
+    };
+}
+
+/* The options every DEFLATE stream takes, whatever it's wrapped in. Returns whether key was one. */
+fn deflate_option(options: &mut DeflateOptions, key: &str, value: &str) -> bool {
+    match key {
+        "byte" => options.byte = bomb_byte(value),
+        "literals" => options.stored_literals = stored_literals(value),
+        "window_bits" => options.window_bits = Option::Some(window_bits(value)),
+        _ => return false,
+    }
+    return true;
+}
+
+/* A compression level for a header to claim, 0 to 9 */
+fn level(value: &str) -> u8 {
+    return match value.parse::<u8>() {
+        Ok(level) if level <= 9 => level,
+        _ => panic!("Invalid level {}", value),
+    };
+}
+
+fn corruption(key: &str, value: &str) -> Option<Corruption> {
+    return Option::Some(Corruption::parse(value)
+        .unwrap_or_else(|| panic!("Invalid {} corruption {}", key, value)));
+}
+
+impl Recipe {
+    /* Parses [content encoding] [size] [payload]... */
+    pub fn parse(args: &[String]) -> Recipe {
+        let encodings = split_encodings(&args[0]);
+        let size = BigUint::from_str(&args[1]).expect("Invalid size given");
+        let mut parts = Vec::<Part>::new();
+
+        let mut cur_arg = 2;
+        while cur_arg < args.len() {
+            if args[cur_arg] == "-f" {
+                cur_arg += 1;
+                if cur_arg >= args.len() {
+                    panic!("-f: missing file");
+                }
+                parts.push(Part::File(args[cur_arg].clone()));
+                cur_arg += 1;
+                continue;
+            }
+
+            if args[cur_arg] == "-p" {
+                cur_arg += 1;
+                if cur_arg >= args.len() || args[cur_arg].is_empty() {
+                    panic!("-p: missing pattern");
+                }
+                parts.push(Part::Pattern(args[cur_arg].clone()));
+                cur_arg += 1;
+                continue;
+            }
+
+            let byte: u8;
+            if args[cur_arg] == "-l" {
+                cur_arg += 1;
+                if cur_arg >= args.len() {
+                    panic!("-l: missing character");
+                }
+                byte = args[cur_arg].chars().next().expect("-l: missing character") as u8;
+                cur_arg += 1;
+            } else if args[cur_arg] == "-L" {
+                cur_arg += 1;
+                if cur_arg >= args.len() {
+                    panic!("-L: missing character");
+                }
+                byte = args[cur_arg].parse::<u8>().expect("-L: invalid character");
+                cur_arg += 1;
+            } else {
+                panic!("Invalid flag {}", args[cur_arg]);
+            }
+
+            parts.push(Part::Byte(byte));
+        }
+
+        return Recipe {
+            encodings: encodings,
+            size: size,
+            parts: parts,
+        };
+    }
+
+    /* The arguments parse would take to give this recipe back */
+    pub fn args(&self) -> Vec<String> {
+        let mut ret = vec![self.encodings.join(","), self.size.to_string()];
+        for part in self.parts.iter() {
+            match part {
+                Part::File(path) => ret.extend(["-f".to_string(), path.clone()]),
+                Part::Byte(byte) => ret.extend(["-L".to_string(), byte.to_string()]),
+                Part::Pattern(pattern) => ret.extend(["-p".to_string(), pattern.clone()]),
+            }
+        }
+        return ret;
+    }
+
+    /* The encodings that add a layer, which is all of them but identity */
+    pub fn layer_encodings(&self) -> Vec<&String> {
+        return self.encodings.iter().filter(|e| parse_encoding(e).0 != "identity").collect();
+    }
+
+    /* The Content-Encoding header for the layers, in the order they were applied. deflate-raw
+     * isn't an HTTP content coding, it's sent as deflate for the clients that take raw streams
+     * under that name. */
+    pub fn content_encoding(&self) -> String {
+        return self.layer_encodings().iter()
+            .map(|e| match parse_encoding(e).0 {
+                "deflate-raw" => "deflate",
+                name => name,
+            })
+            .collect::<Vec<&str>>()
+            .join(", ");
+    }
+
+    /* The innermost, unencoded layer */
+    pub fn segments(&self) -> Vec<Segment> {
+        let mut data = Vec::<Segment>::new();
+        for part in self.parts.iter() {
+            match part {
+                Part::File(path) => {
+                    let mut file = std::fs::File::open(path).expect("Failed to open file");
+                    let mut contents: Vec<u8> = vec![];
+                    file.read_to_end(&mut contents).expect("Failed to read file");
+                    data.push(Segment::Block(Block::new(contents.into_boxed_slice())));
+                }
+                Part::Byte(byte) => {
+                    data.push(Segment::Bomb(Bomb::new(Box::new([*byte]))));
+                }
+                Part::Pattern(pattern) => {
+                    data.push(Segment::Bomb(Bomb::new(pattern.as_bytes().into())));
+                }
+            }
+        }
+        return data;
+    }
+
+    /* Builds and fills the fully encoded payload.
+     *
+     * A gzip layer with a member_size can only tell how big its members are once it's filled, so
+     * it's built with more and more members until they all fit, and then with as few as fit. A
+     * member_size smaller than a byte of bomb above the layer decodes to can never fit, and panics
+     * once more members stop making them smaller. */
+    pub fn build(&self) -> Payload {
+        let mut members = vec![Option::<usize>::None; self.encodings.len()];
+        /* for each member_size layer, the most members known not to fit and the fewest known to */
+        let mut bounds = vec![(0, Option::<usize>::None); self.encodings.len()];
+        /* and the largest member with lo members */
+        let mut largest = vec![Option::<BigUint>::None; self.encodings.len()];
+        loop {
+            let (payload, caps) = self.build_with(&members);
+            let layers = self.layer_encodings().len();
+
+            let mut done = true;
+            for (i, depth, cap, count) in caps {
+                let mut layer = &payload;
+                for _j in depth + 1..layers {
+                    layer = layer.child().unwrap();
+                }
+                let most = layer.member_sizes().into_iter().max().unwrap();
+                let (lo, hi) = &mut bounds[i];
+                if most <= cap {
+                    *hi = Option::Some(count);
+                } else if count > *lo {
+                    /* members stop shrinking once they're down to their literals, or a byte of
+                     * bomb each */
+                    if hi.is_none() && largest[i].as_ref().is_some_and(|l| *l <= most) {
+                        panic!("gzip members can't be made smaller than {} bytes, which is more \
+                                than member_size={}", most, cap);
+                    }
+                    *lo = count;
+                    largest[i] = Option::Some(most.clone());
+                }
+
+                let next = match *hi {
+                    Option::Some(hi) if hi <= *lo + 1 => hi,
+                    Option::Some(hi) => (*lo + hi) / 2,
+                    Option::None => {
+                        /* members shrink about in proportion, but at least double */
+                        let guess = (BigUint::from(*lo) * &most + &cap - 1u8) / &cap;
+                        match usize::try_from(&guess) {
+                            Ok(n) => std::cmp::max(n, *lo * 2),
+                            Err(_) => usize::MAX,
+                        }
+                    }
+                };
+                if next > MAX_MEMBERS {
+                    if *lo >= MAX_MEMBERS {
+                        panic!("gzip members can't be made as small as member_size={} with {} of them",
+                                cap, MAX_MEMBERS);
+                    }
+                    members[i] = Option::Some(MAX_MEMBERS);
+                    done = false;
+                } else if next != count {
+                    members[i] = Option::Some(next);
+                    done = false;
+                }
+            }
+            if done {
+                return payload;
+            }
+        }
+    }
+
+    /* build(), with the gzip layers at any index of members split into that many members. Also
+     * returns the index, depth, member_size and member count of each layer that has a size. */
+    fn build_with(&self, members: &[Option<usize>])
+            -> (Payload, Vec<(usize, usize, BigUint, usize)>) {
+        let mut payload = Payload::new(self.segments().into_boxed_slice());
+        let mut caps = Vec::<(usize, usize, BigUint, usize)>::new();
+        let mut depth = 0;
+
+        for (i, method) in self.encodings.iter().enumerate() {
+            let (name, options) = parse_encoding(method);
+            if name == "gzip" || name == "x-gzip" {
+                let mut gzip_options = GzipOptions::default();
+                let mut member_size = Option::<BigUint>::None;
+                for (key, value) in options {
+                    if deflate_option(&mut gzip_options.deflate, key, value) {
+                        continue;
+                    }
+                    match key {
+                        "level" => gzip_options.level = Option::Some(level(value)),
+                        "mtime" => gzip_options.mtime = value.parse::<u32>()
+                            .unwrap_or_else(|_| panic!("Invalid mtime {}", value)),
+                        "os" => gzip_options.os = Option::Some(byte_value(key, value)),
+                        "name" => gzip_options.name = Option::Some(value.as_bytes().to_vec()),
+                        "comment" => gzip_options.comment = Option::Some(value.as_bytes().to_vec()),
+                        "extra" => gzip_options.extra.push(extra_subfield(value)),
+                        "hcrc" => gzip_options.header_crc = yes_no(key, value),
+                        "members" => gzip_options.members = value.parse::<usize>()
+                            .unwrap_or_else(|_| panic!("Invalid members {}", value)),
+                        "member_size" => member_size = Option::Some(BigUint::from_str(value).ok()
+                            .filter(|n| *n > BigUint::ZERO)
+                            .unwrap_or_else(|| panic!("Invalid member_size {}", value))),
+                        "small_isize" => gzip_options.small_isize = Option::Some(value
+                            .parse::<usize>()
+                            .unwrap_or_else(|_| panic!("Invalid small_isize {}", value))),
+                        "crc32" => gzip_options.crc32 = corruption(key, value),
+                        "isize" => gzip_options.isize = corruption(key, value),
+                        _ => panic!("Invalid {} option {}", name, key),
+                    }
+                }
+                if let Option::Some(n) = members[i] {
+                    gzip_options.members = n;
+                }
+                if let Option::Some(cap) = member_size {
+                    caps.push((i, depth, cap, std::cmp::max(gzip_options.members, 1)));
+                }
+                payload = gzip_with(payload, &gzip_options);
+            } else if name == "deflate" {
+                let mut zlib_options = ZlibOptions::default();
+                for (key, value) in options {
+                    if deflate_option(&mut zlib_options.deflate, key, value) {
+                        continue;
+                    }
+                    match key {
+                        "level" => zlib_options.level = Option::Some(level(value)),
+                        "adler32" => zlib_options.adler32 = corruption(key, value),
+                        _ => panic!("Invalid deflate option {}", key),
+                    }
+                }
+                payload = zlib_with(payload, &zlib_options);
+            } else if name == "deflate-raw" {
+                let mut deflate_options = DeflateOptions::default();
+                for (key, value) in options {
+                    if !deflate_option(&mut deflate_options, key, value) {
+                        panic!("Invalid deflate-raw option {}", key);
+                    }
+                }
+                payload = deflate_raw_with(payload, &deflate_options);
+            } else if name == "identity" {
+                if let Option::Some((key, _value)) = options.first() {
+                    panic!("Invalid identity option {}", key);
+                }
+            } else {
+                panic!("Invalid method {}", method);
+            }
+            if name != "identity" {
+                depth += 1;
+            }
+        }
+
+        payload.fill(&self.size);
+        return (payload, caps);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::checksum::{ByteOrder, ChecksumEngine};
+
+    fn build(encodings: &str) -> Vec<u8> {
+        let args: Vec<String> = [encodings, "10", "-l", "a"].iter().map(|s| s.to_string()).collect();
+        let mut data = Vec::<u8>::new();
+        Recipe::parse(&args).build().write(&mut data).unwrap();
+        return data;
+    }
+
+    #[test]
+    fn test_encodings() {
+        assert_eq!(split_encodings("gzip, gzip(crc32=flip,isize=+1),,deflate"),
+                ["gzip", "gzip(crc32=flip,isize=+1)", "deflate"]);
+        assert_eq!(parse_encoding("gzip( crc32=0x10 , isize=-1)"),
+                ("gzip", vec![("crc32", "0x10"), ("isize", "-1")]));
+        assert_eq!(parse_encoding("deflate"), ("deflate", vec![]));
+
+        let args: Vec<String> = ["gzip,deflate(byte=0)", "10", "-l", "a", "-p", "ab", "-f", "x"]
+            .iter().map(|s| s.to_string()).collect();
+        assert_eq!(Recipe::parse(&args).args(),
+                ["gzip,deflate(byte=0)", "10", "-L", "97", "-p", "ab", "-f", "x"]);
+    }
+
+    #[test]
+    fn test_bomb_byte() {
+        assert_eq!(bomb_byte("auto"), Option::None);
+        assert_eq!(bomb_byte("0xff"), Option::Some(0xff));
+        assert_eq!(bomb_byte("65"), Option::Some(65));
+
+        /* the inner layer's bombs are 0x00, and the outer one still inflates to it */
+        let data = build("gzip(byte=0x00), deflate");
+        let (inner, _len) = crate::payload::inflate::inflate(&data[2..]).unwrap();
+        let (content, _len) = crate::payload::inflate::inflate(&inner[10..]).unwrap();
+        assert!(content.iter().all(|b| *b == b'a'));
+        assert!(inner.windows(1000).any(|w| w.iter().all(|b| *b == 0x00)));
+        assert!(!inner.windows(1000).any(|w| w.iter().all(|b| *b == 0x55)));
+    }
+
+    /* both ways give the same data, and the stored one is no smaller */
+    #[test]
+    fn test_literals() {
+        /* some text, for the Huffman codes to have something to work with */
+        let path = std::env::temp_dir().join(format!("ied-literals-test-{}", std::process::id()));
+        let text = "<html><head><title>Hello</title></head><body><p>Hello, world!</p></body>\n"
+            .repeat(20);
+        std::fs::write(&path, text).unwrap();
+        let path_str = path.to_str().unwrap();
+
+        let args: Vec<String> = ["gzip(literals=stored)", "10", "-f", path_str, "-l", "a"]
+            .iter().map(|s| s.to_string()).collect();
+        let mut stored = Vec::<u8>::new();
+        Recipe::parse(&args).build().write(&mut stored).unwrap();
+        let args: Vec<String> = ["gzip(literals=huffman)", "10", "-f", path_str, "-l", "a"]
+            .iter().map(|s| s.to_string()).collect();
+        let mut huffman = Vec::<u8>::new();
+        Recipe::parse(&args).build().write(&mut huffman).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let (a, _len) = crate::payload::inflate::inflate(&stored[10..]).unwrap();
+        let (b, _len) = crate::payload::inflate::inflate(&huffman[10..]).unwrap();
+        assert_eq!(a, b);
+        assert!(huffman.len() < stored.len());
+    }
+
+    #[test]
+    fn test_aliases() {
+        assert_eq!(build("x-gzip"), build("gzip"));
+        assert_eq!(build("identity, gzip, identity"), build("gzip"));
+
+        /* deflate-raw is deflate without the zlib wrapper */
+        let zlib = build("deflate");
+        assert_eq!(build("deflate-raw"), zlib[2..zlib.len() - 4]);
+        let (content, len) = crate::payload::inflate::inflate(&build("deflate-raw")).unwrap();
+        assert!(content.iter().all(|b| *b == b'a'));
+        assert_eq!(len, zlib.len() - 6);
+    }
+
+    #[test]
+    fn test_content_encoding() {
+        let recipe = |encodings: &str| Recipe::parse(&[encodings, "1", "-l", "a"].map(String::from));
+        assert_eq!(recipe("deflate-raw,gzip").content_encoding(), "deflate, gzip");
+        assert_eq!(recipe("deflate-raw(window_bits=9)").content_encoding(), "deflate");
+        assert_eq!(recipe("x-gzip, identity, deflate").content_encoding(), "x-gzip, deflate");
+        assert_eq!(recipe("identity").content_encoding(), "");
+    }
+
+    /* levels and times only change the header */
+    #[test]
+    fn test_header_options() {
+        let good = build("gzip");
+        let data = build("gzip(level=1,mtime=305419896)");
+        assert_eq!(&data[..10], &[0x1f, 0x8b, 0x08, 0x00, 0x78, 0x56, 0x34, 0x12, 0x04, 0xff]);
+        assert_eq!(&data[10..], &good[10..]);
+
+        let good = build("deflate");
+        assert_eq!(&good[..2], &[0x08, 0x1d]);
+        for (level, flags) in [(0, 0x1d), (1, 0x1d), (5, 0x5b), (6, 0x99), (9, 0xd7)] {
+            let data = build(&format!("deflate(level={})", level));
+            assert_eq!(data[1], flags);
+            assert_eq!((data[0] as u16 * 256 + data[1] as u16) % 31, 0);
+            assert_eq!(&data[2..], &good[2..]);
+        }
+    }
+
+    #[test]
+    fn test_gzip_header() {
+        let good = build("gzip");
+        let data = build("gzip(os=3,name=a.html,comment=hi,extra=Ap:0102,hcrc=yes)");
+        let header = [
+            0x1f, 0x8b, 0x08, 0x1e, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03,
+            0x06, 0x00, b'A', b'p', 0x02, 0x00, 0x01, 0x02,
+            b'a', b'.', b'h', b't', b'm', b'l', 0x00,
+            b'h', b'i', 0x00,
+        ];
+        assert_eq!(&data[..header.len()], &header);
+        let mut crc = crate::payload::crc32::Crc32Engine::new();
+        crc.apply(&header);
+        assert_eq!(&data[header.len()..header.len() + 2], &crc.bytes(ByteOrder::Little)[..2]);
+        assert_eq!(&data[header.len() + 2..], &good[10..]);
+
+        assert_eq!(extra_subfield("xy:"), (*b"xy", vec![]));
+        assert_eq!(extra_subfield("xy:00ff"), (*b"xy", vec![0x00, 0xff]));
+    }
+
+    #[test]
+    fn test_member_size() {
+        let members = |encodings: &str| -> Vec<BigUint> {
+            let args: Vec<String> = [encodings, "20", "-p", "abc", "-l", "a"]
+                .iter().map(|s| s.to_string()).collect();
+            let payload = Recipe::parse(&args).build();
+            return payload.child().unwrap().member_sizes();
+        };
+
+        let cap = BigUint::from(10000000u32);
+        let sizes = members("gzip(member_size=10000000), gzip");
+        assert!(sizes.len() > 2);
+        assert!(sizes.iter().all(|s| *s <= cap));
+
+        /* and no fewer would do */
+        let fewer = members(&format!("gzip(members={}), gzip", sizes.len() - 1));
+        assert!(fewer.iter().any(|s| *s > cap));
+
+        /* the size is divided up, so the whole is the same give or take what each member adds */
+        let one = members("gzip, gzip")[0].clone();
+        let total: BigUint = sizes.iter().sum();
+        assert!(total >= one && total - one < BigUint::from(sizes.len() * 2 * 1032 * 1032));
+    }
+
+    /* only the trailer of the chosen layer changes */
+    /* a byte of bomb in the layer above is already more than 100000 bytes of the layer's */
+    #[test]
+    #[should_panic(expected = "can't be made smaller than 2398627 bytes")]
+    fn test_member_size_too_small() {
+        let args: Vec<String> = ["gzip(member_size=100000),gzip", "3", "-l", "a"]
+            .iter().map(|s| s.to_string()).collect();
+        Recipe::parse(&args).build();
+    }
+
+    /* The members one after another, each checked against its trailer */
+    fn gunzip(mut data: &[u8]) -> Vec<Vec<u8>> {
+        let mut ret = Vec::<Vec<u8>>::new();
+        while !data.is_empty() {
+            let (content, len) = crate::payload::inflate::inflate(&data[10..]).unwrap();
+            let mut crc = crate::payload::crc32::Crc32Engine::new();
+            crc.apply(&content);
+            assert_eq!(&data[10 + len..14 + len], &crc.bytes(ByteOrder::Little)[..]);
+            assert_eq!(&data[14 + len..18 + len], &(content.len() as u32).to_le_bytes());
+            ret.push(content);
+            data = &data[18 + len..];
+        }
+        return ret;
+    }
+
+    /* the last member decodes to the end of the last bomb, and the whole stream to everything */
+    #[test]
+    fn test_small_isize() {
+        for small in [1, 7, 1000] {
+            let encodings = format!("gzip(small_isize={}), gzip", small);
+            let args: Vec<String> = [&encodings, "10", "-p", "xyz", "-p", "abcd"]
+                .iter().map(|s| s.to_string()).collect();
+            let payload = Recipe::parse(&args).build();
+            let mut data = Vec::<u8>::new();
+            payload.write(&mut data).unwrap();
+            let mut expected = Vec::<u8>::new();
+            payload.child().unwrap().child().unwrap().write(&mut expected).unwrap();
+
+            let outer = gunzip(&data);
+            assert_eq!(outer.len(), 1);
+            let members = gunzip(&outer[0]);
+            assert_eq!(members.len(), 2);
+            assert_eq!(members[1].len(), small);
+            assert_eq!(members.concat(), expected);
+            assert!(expected.len() > 10 * 1032 * 1032);
+        }
+    }
+
+    #[test]
+    fn test_corruption() {
+        let good = build("gzip");
+        let n = good.len();
+
+        let bad = build("gzip(crc32=flip:8)");
+        assert_eq!(&bad[..n - 8], &good[..n - 8]);
+        assert_eq!(bad[n - 7], good[n - 7] ^ 1);
+        assert_eq!(&bad[n - 6..], &good[n - 6..]);
+
+        let bad = build("gzip(isize=0x01020304)");
+        assert_eq!(&bad[..n - 4], &good[..n - 4]);
+        assert_eq!(&bad[n - 4..], &[4, 3, 2, 1]);
+
+        let good = build("deflate");
+        let bad = build("deflate(adler32=+1)");
+        let n = good.len();
+        assert_eq!(&bad[..n - 1], &good[..n - 1]);
+        assert_eq!(bad[n - 1], good[n - 1].wrapping_add(1));
+    }
+}
@@ -0,0 +1,342 @@
+use crate::cache::{read_recipes, recipe_args, Cache};
+use crate::metrics::Metrics;
+use crate::payload::Delivery;
+use crate::range::{self, RangeRequest};
+use crate::recipe::Recipe;
+use crate::websocket;
+use num::BigUint;
+use std::io::{self, BufRead, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+/* ied serve: the recipes of a recipes file (the same one ied cache build takes) over HTTP.
+ *
+ * GET /[name] sends that recipe's payload with its encodings as the Content-Encoding, and honours
+ * Range, so a client that retries from where it stalled gets the rest. A WebSocket handshake on
+ * the same path that offers permessage-deflate gets the recipe's content as one compressed message
+ * instead, which is a single deflate-raw layer since that's all the client inflates. GET /metrics
+ * has the Prometheus metrics for everything served. Each connection gets a thread of its own and
+ * one response, and how much of the payload the client took is logged to stderr.
+ *
+ * Payloads come from a Cache, so each one is only built once, and again when its recipe changes. */
+pub struct Server {
+    recipes: Vec<(String, String)>,
+    cache: Mutex<Cache>,
+    metrics: Metrics,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+/* The most a request line and its headers can take up */
+const MAX_HEAD: usize = 16384;
+
+impl Request {
+    /* Header names aren't case sensitive */
+    fn header(&self, name: &str) -> Option<&str> {
+        return self.headers.iter()
+            .find(|(n, _v)| n.eq_ignore_ascii_case(name))
+            .map(|(_n, v)| v.as_str());
+    }
+}
+
+/* Reads a request line and headers, or None if they're malformed or too long */
+fn read_request(input: &mut impl BufRead) -> Option<Request> {
+    let mut head = Vec::<String>::new();
+    let mut len = 0;
+    loop {
+        let mut line = Vec::<u8>::new();
+        let n = io::Read::take(&mut *input, (MAX_HEAD - len) as u64).read_until(b'\n', &mut line).ok()?;
+        if n == 0 || line.last() != Option::Some(&b'\n') {
+            return Option::None;
+        }
+        len += n;
+        let line = String::from_utf8(line).ok()?;
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        head.push(line.to_string());
+    }
+
+    let mut request_line = head.first()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let mut headers = Vec::<(String, String)>::new();
+    for line in head[1..].iter() {
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    return Option::Some(Request {
+        method: method,
+        path: path,
+        headers: headers,
+    });
+}
+
+/* The log line for one response, e.g. "127.0.0.1:5000 /a sent 1500 bytes of a 2361 byte payload,
+ * disconnected". What was sent includes the response's headers. */
+fn log_line(peer: &str, path: &str, delivery: &Delivery, size: &BigUint) -> String {
+    let mut ret = format!("{} {} sent {} bytes of a {} byte payload", peer, path, delivery.bytes,
+            size);
+    if delivery.disconnected {
+        ret += ", disconnected";
+    }
+    return ret;
+}
+
+fn write_status(output: &mut impl io::Write, status: &str) -> io::Result<()> {
+    write!(output, "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status)?;
+    return output.flush();
+}
+
+impl Server {
+    pub fn new(cache: Cache, recipes: Vec<(String, String)>) -> Server {
+        return Server {
+            recipes: recipes,
+            cache: Mutex::new(cache),
+            metrics: Metrics::new(),
+        };
+    }
+
+    /* The recipe served at path, if any */
+    fn find(&self, path: &str) -> Option<&(String, String)> {
+        let name = path.strip_prefix('/')?;
+        return self.recipes.iter().find(|(n, _r)| n == name);
+    }
+
+    /* Answers the one request on a connection */
+    pub fn handle(&self, stream: TcpStream) -> io::Result<()> {
+        let _connection = self.metrics.connection();
+        let mut output = &stream;
+        let request = match read_request(&mut BufReader::new(&stream)) {
+            Option::Some(request) => request,
+            Option::None => return write_status(&mut output, "400 Bad Request"),
+        };
+
+        if request.method != "GET" {
+            return write_status(&mut output, "405 Method Not Allowed");
+        }
+        if request.path == "/metrics" {
+            return self.metrics.write_response(&mut output);
+        }
+        let (name, text) = match self.find(&request.path) {
+            Option::Some(recipe) => recipe,
+            Option::None => return write_status(&mut output, "404 Not Found"),
+        };
+        let recipe = Recipe::parse(&recipe_args(text));
+        let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or("-".to_string());
+
+        if request.header("Upgrade").is_some_and(|u| u.eq_ignore_ascii_case("websocket")) {
+            let key = request.header("Sec-WebSocket-Key");
+            let offer = request.header("Sec-WebSocket-Extensions").and_then(websocket::negotiate);
+            let (key, (extension, window)) = match (key, offer) {
+                (Option::Some(key), Option::Some(offer)) => (key, offer),
+                _ => return write_status(&mut output, "400 Bad Request"),
+            };
+            /* cached as a recipe of its own, one for each window size */
+            let mut message = recipe;
+            message.encodings = vec![match window {
+                Option::Some(bits) => format!("deflate-raw(window_bits={})", bits),
+                Option::None => "deflate-raw".to_string(),
+            }];
+            let (payload, final_size) = self.cache.lock().unwrap()
+                .load(&format!("{}.{}", name, message.encodings[0]), &message.args().join(" "));
+            websocket::write_handshake(&mut output, key, Option::Some(&extension))?;
+            let delivery = websocket::write_message(&mut output, &payload, true)?;
+            eprintln!("{}", log_line(&peer, &request.path, &delivery, &payload.size()));
+            self.metrics.record(name, text, &payload.size(), &final_size, &delivery);
+            return Ok(());
+        }
+
+        let (payload, final_size) = self.cache.lock().unwrap().load(name, text);
+        let ranges = match request.header("Range") {
+            Option::Some(header) => range::parse(header, &payload.size()),
+            Option::None => RangeRequest::Full,
+        };
+        let encoding = recipe.content_encoding();
+        let mut headers = vec![("Content-Type", "text/html"), ("Connection", "close")];
+        if !encoding.is_empty() {
+            headers.push(("Content-Encoding", &encoding));
+        }
+        let delivery = range::write_response(&mut output, &payload, &ranges, &headers)?;
+        eprintln!("{}", log_line(&peer, &request.path, &delivery, &payload.size()));
+        self.metrics.record(name, text, &payload.size(), &final_size, &delivery);
+        return Ok(());
+    }
+}
+
+/* ied serve [address] [cache directory] [recipes] */
+pub fn main(args: &[String]) {
+    if args.len() != 3 {
+        println!("Usage: ied serve [address] [cache directory] [recipes]");
+        return;
+    }
+
+    let listener = TcpListener::bind(&args[0]).expect("Failed to listen");
+    let recipes = read_recipes(&args[2]);
+    /* build whatever's missing or out of date before taking requests */
+    let mut cache = Cache::open(&args[1]);
+    for (name, text) in recipes.iter() {
+        cache.get(name, text);
+    }
+    let server = Arc::new(Server::new(cache, recipes));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_e) => continue,
+        };
+        let server = server.clone();
+        std::thread::spawn(move || {
+            /* the client going away is all that can go wrong here */
+            let _ = server.handle(stream);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::inflate::inflate_window;
+    use std::io::{Read, Write};
+
+    /* The whole response to one request */
+    fn get(server: &Server, request: &str) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let request = request.to_string();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = Vec::<u8>::new();
+            stream.read_to_end(&mut response).unwrap();
+            return response;
+        });
+        let (stream, _address) = listener.accept().unwrap();
+        server.handle(stream).unwrap();
+        return client.join().unwrap();
+    }
+
+    /* A server for recipes with a fresh cache directory, removed again by the caller */
+    fn server(test: &str, recipes: &[(&str, &str)]) -> (Server, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("ied-serve-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let recipes = recipes.iter().map(|(n, r)| (n.to_string(), r.to_string())).collect();
+        return (Server::new(Cache::open(dir.to_str().unwrap()), recipes), dir);
+    }
+
+    /* The head of a response as text, and its body */
+    fn split(response: &[u8]) -> (String, &[u8]) {
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        return (String::from_utf8(response[..end].to_vec()).unwrap(), &response[end..]);
+    }
+
+    #[test]
+    fn test_serve() {
+        let text = "gzip,gzip 100 -p <p>a</p> -l a";
+        let (server, dir) = server("serve", &[("a", text)]);
+        let mut expected = Vec::<u8>::new();
+        Recipe::parse(&recipe_args(text)).build().write(&mut expected).unwrap();
+
+        let response = get(&server, "GET /a HTTP/1.1\r\nHost: x\r\n\r\n");
+        let mut sent = response.len();
+        let (head, body) = split(&response);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("\r\nContent-Encoding: gzip, gzip\r\n"));
+        assert_eq!(body, expected.as_slice());
+
+        let response = get(&server, "GET /a HTTP/1.1\r\nrange: bytes=10-19\r\n\r\n");
+        sent += response.len();
+        let (head, body) = split(&response);
+        assert!(head.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(head.contains(&format!("\r\nContent-Range: bytes 10-19/{}\r\n", expected.len())));
+        assert_eq!(body, &expected[10..20]);
+
+        /* a WebSocket gets the content itself, in a window of the client's choosing */
+        let response = get(&server, "GET /a HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\
+                Sec-WebSocket-Extensions: permessage-deflate; server_max_window_bits=9\r\n\r\n");
+        let (head, frame) = split(&response);
+        /* the handshake isn't part of the payload */
+        sent += frame.len();
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(head.contains("server_max_window_bits=9"));
+        assert_eq!(frame[0], 0xc1);
+        let (content, _len) = inflate_window(&frame[4..frame.len() - 1], 512).unwrap();
+        let mut recipe = Recipe::parse(&recipe_args(text));
+        recipe.encodings = vec!["deflate-raw(window_bits=9)".to_string()];
+        let mut expected_content = Vec::<u8>::new();
+        recipe.build().child().unwrap().write(&mut expected_content).unwrap();
+        assert_eq!(content, expected_content);
+        let response = get(&server, "GET /a HTTP/1.1\r\nUpgrade: websocket\r\n\r\n");
+        assert!(split(&response).0.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let response = get(&server, "GET /b HTTP/1.1\r\n\r\n");
+        assert!(split(&response).0.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = get(&server, "POST /a HTTP/1.1\r\n\r\n");
+        assert!(split(&response).0.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+        /* each response to /a is counted under its name and recipe */
+        let response = get(&server, "GET /metrics HTTP/1.1\r\n\r\n");
+        let (head, body) = split(&response);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let labels = format!("{{rule=\"a\",recipe=\"{}\"}}", text);
+        assert!(body.contains(&format!("ied_requests_total{} 3\n", labels)));
+        assert!(body.contains(&format!("ied_sent_bytes_total{} {}\n", labels, sent)));
+        assert!(body.contains(&format!("ied_transfers_complete_total{} 3\n", labels)));
+        assert!(body.contains("ied_active_connections 1\n"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /* what's sent is what's in the cache, not built again for every request */
+    #[test]
+    fn test_cached() {
+        let (server, dir) = server("cached", &[("a", "gzip 100 -l a")]);
+        let response = get(&server, "GET /a HTTP/1.1\r\n\r\n");
+        let entry = server.cache.lock().unwrap().get("a", "gzip 100 -l a").file.clone();
+        assert_eq!(split(&response).1, std::fs::read(dir.join(&entry)).unwrap().as_slice());
+
+        std::fs::write(dir.join(&entry), b"cached").unwrap();
+        let response = get(&server, "GET /a HTTP/1.1\r\n\r\n");
+        assert_eq!(split(&response).1, b"cached");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_log_line() {
+        let size = BigUint::from(2361u32);
+        let delivery = Delivery {
+            bytes: 1500,
+            disconnected: true,
+        };
+        assert_eq!(log_line("127.0.0.1:5000", "/a", &delivery, &size),
+                "127.0.0.1:5000 /a sent 1500 bytes of a 2361 byte payload, disconnected");
+        let delivery = Delivery {
+            bytes: 2361,
+            disconnected: false,
+        };
+        assert_eq!(log_line("127.0.0.1:5000", "/a", &delivery, &size),
+                "127.0.0.1:5000 /a sent 2361 bytes of a 2361 byte payload");
+    }
+
+    /* a request line the path can't be taken apart from is answered, not a panic */
+    #[test]
+    fn test_bad_path() {
+        let (server, dir) = server("bad-path", &[("a", "gzip 10 -l a")]);
+        for request in ["GET  HTTP/1.1\r\n\r\n", "GET é HTTP/1.1\r\n\r\n", "GET a HTTP/1.1\r\n\r\n"] {
+            let response = get(&server, request);
+            assert!(split(&response).0.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        }
+        let response = get(&server, "GET\r\n\r\n");
+        assert!(split(&response).0.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
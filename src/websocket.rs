@@ -0,0 +1,276 @@
+use crate::payload::{delivered, write_all, Delivery, Payload};
+use num::BigUint;
+use std::io;
+
+/* WebSocket messages compressed with permessage-deflate (RFC 7692).
+ *
+ * A message's payload is a raw DEFLATE stream, so deflate_raw() layers can be sent as they are and
+ * the client inflates the whole bomb into one message. */
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([chunk[4*i], chunk[4*i+1], chunk[4*i+2], chunk[4*i+3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i-3] ^ w[i-8] ^ w[i-14] ^ w[i-16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for i in 0..80 {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(w[i]);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+
+        for (x, y) in h.iter_mut().zip([a, b, c, d, e]) {
+            *x = x.wrapping_add(y);
+        }
+    }
+
+    let mut ret = [0u8; 20];
+    for i in 0..5 {
+        ret[4*i..4*i+4].copy_from_slice(&h[i].to_be_bytes());
+    }
+    return ret;
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut ret = String::new();
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | (*chunk.get(2).unwrap_or(&0) as u32);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                ret.push(ALPHABET[((n >> (18 - 6*i)) & 0x3f) as usize] as char);
+            } else {
+                ret.push('=');
+            }
+        }
+    }
+    return ret;
+}
+
+/* The Sec-WebSocket-Accept value for a client's Sec-WebSocket-Key */
+pub fn accept_key(key: &str) -> String {
+    return base64(&sha1(format!("{}{}", key.trim(), GUID).as_bytes()));
+}
+
+/* Picks the first usable permessage-deflate offer out of a Sec-WebSocket-Extensions header and
+ * returns our response to it, along with the server_max_window_bits the client asked for, if any.
+ * None if the client didn't offer it.
+ *
+ * Every message is compressed on its own, so we always ask for server_no_context_takeover. A
+ * window size is agreed to as it is, and messages then have to be built with it as their
+ * window_bits, so that nothing refers back further than the client keeps. An offer with a window
+ * size that isn't 8 to 15 is declined (RFC 7692 section 7.1.2.1). */
+pub fn negotiate(extensions: &str) -> Option<(String, Option<u8>)> {
+    'offers: for offer in extensions.split(',') {
+        let mut params = offer.split(';').map(|p| p.trim());
+        if params.next() != Option::Some("permessage-deflate") {
+            continue;
+        }
+
+        let mut ret = "permessage-deflate; server_no_context_takeover".to_string();
+        let mut window = Option::<u8>::None;
+        for param in params {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            if name.trim() != "server_max_window_bits" {
+                continue;
+            }
+            match value.trim().trim_matches('"').parse::<u8>() {
+                Ok(bits) if (8..=15).contains(&bits) => window = Option::Some(bits),
+                _ => continue 'offers,
+            }
+            ret += &format!("; server_max_window_bits={}", window.unwrap());
+        }
+        return Option::Some((ret, window));
+    }
+    return Option::None;
+}
+
+/* Writes the 101 response completing the handshake. extension is the result of negotiate(). */
+pub fn write_handshake(output: &mut impl io::Write, key: &str, extension: Option<&str>)
+        -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n", accept_key(key));
+    if let Option::Some(e) = extension {
+        head += &format!("Sec-WebSocket-Extensions: {}\r\n", e);
+    }
+    head += "\r\n";
+    let mut bytes: usize = 0;
+    write_all(output, head.as_bytes(), &mut bytes)?;
+    return output.flush();
+}
+
+/* The header of a single unmasked frame with RSV1 set, which marks a compressed message. */
+fn frame_header(text: bool, len: u64) -> Vec<u8> {
+    let opcode: u8 = if text { 0x1 } else { 0x2 };
+    let mut ret = vec![0x80 | 0x40 | opcode];
+    if len < 126 {
+        ret.push(len as u8);
+    } else if len <= 0xffff {
+        ret.push(126);
+        ret.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        ret.push(127);
+        ret.extend_from_slice(&len.to_be_bytes());
+    }
+    return ret;
+}
+
+/* Sends a filled deflate_raw() payload as one compressed message. If negotiate() agreed to a
+ * window size, the payload's DEFLATE layer has to have been built with it.
+ *
+ * RFC 7692 section 7.2.1 has the sender append an empty stored block and then strip its final
+ * 0x00 0x00 0xff 0xff. Our streams end on a byte boundary with BFINAL set, so all that's left of
+ * that block is a single 0x00 (section 7.2.3.3). */
+pub fn write_message(output: &mut impl io::Write, payload: &Payload, text: bool)
+        -> io::Result<Delivery> {
+    let size = payload.size();
+    /* the most significant bit of a 64 bit length must be 0 (RFC 6455 section 5.2) */
+    let len = &size + 1u8;
+    if len >= BigUint::from(1u64 << 63) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "WebSocket message too large"));
+    }
+    let len = len.to_u64_digits()[0];
+
+    let mut bytes: usize = 0;
+    let result = (|| {
+        write_all(output, &frame_header(text, len), &mut bytes)?;
+        payload.stream_range(output, &BigUint::ZERO, &size, &mut bytes)?;
+        write_all(output, &[0x00], &mut bytes)?;
+        return output.flush();
+    })();
+    return delivered(result, bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::*;
+    use crate::payload::inflate::inflate_window;
+
+    #[test]
+    fn test_handshake() {
+        /* RFC 6455 section 1.3 */
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"a"), "YQ==");
+
+        assert_eq!(negotiate("x-webkit-deflate-frame"), Option::None);
+        assert_eq!(negotiate("permessage-deflate; client_max_window_bits").unwrap(),
+                ("permessage-deflate; server_no_context_takeover".to_string(), Option::None));
+        assert_eq!(negotiate("foo, permessage-deflate; server_max_window_bits=10").unwrap(),
+                ("permessage-deflate; server_no_context_takeover; server_max_window_bits=10"
+                    .to_string(), Option::Some(10)));
+
+        /* offers with a window we can't use are skipped */
+        assert_eq!(negotiate("permessage-deflate; server_max_window_bits=7"), Option::None);
+        assert_eq!(negotiate("permessage-deflate; server_max_window_bits, \
+                permessage-deflate; server_max_window_bits=\"9\"").unwrap().1, Option::Some(9));
+    }
+
+    /* a client that only keeps 512 bytes can inflate the message */
+    #[test]
+    fn test_small_window() {
+        let (_response, window) = negotiate("permessage-deflate; server_max_window_bits=9").unwrap();
+        /* repeats itself 700 bytes back */
+        let text: Vec<u8> = (0..1400u32)
+            .map(|i| b'a' + ((i % 700).wrapping_mul(2654435761) >> 27) as u8 % 26)
+            .collect();
+        let pattern = b"<div class=\"x\">".repeat(20);
+        assert!(pattern.len() > 256 && pattern.len() <= 512);
+
+        let options = DeflateOptions {
+            window_bits: window,
+            ..DeflateOptions::default()
+        };
+        let mut payload = deflate_raw_with(Payload::new(Box::new([
+            Segment::Block(Block::new(text.clone().into_boxed_slice())),
+            Segment::Bomb(Bomb::new(pattern.clone().into_boxed_slice())),
+            Segment::Block(Block::new(text.clone().into_boxed_slice())),
+        ])), &options);
+        payload.fill(&BigUint::from(100u8));
+        let mut expected = Vec::<u8>::new();
+        payload.child().unwrap().write(&mut expected).unwrap();
+
+        let mut frame = Vec::<u8>::new();
+        write_message(&mut frame, &payload, true).unwrap();
+        let stream = &frame[4..frame.len() - 1];
+        let (content, len) = inflate_window(stream, 512).unwrap();
+        assert_eq!(len, stream.len());
+        assert_eq!(content, expected);
+
+        /* which the default window doesn't manage */
+        let mut payload = deflate_raw(Payload::new(Box::new([
+            Segment::Block(Block::new(text.clone().into_boxed_slice())),
+            Segment::Bomb(Bomb::new(pattern.into_boxed_slice())),
+        ])));
+        payload.fill(&BigUint::from(100u8));
+        let mut stream = Vec::<u8>::new();
+        payload.write(&mut stream).unwrap();
+        assert_eq!(inflate_window(&stream, 512), Option::None);
+    }
+
+    #[test]
+    fn test_message() {
+        let mut payload = deflate_raw(Payload::new(Box::new([
+            Segment::Block(Block::new(Box::new(*b"{\"a\":\""))),
+            Segment::Bomb(Bomb::new(Box::new([0x61]))),
+            Segment::Block(Block::new(Box::new(*b"\"}"))),
+        ])));
+        payload.fill(&BigUint::from(200u8));
+        let mut stream = Vec::<u8>::new();
+        payload.write(&mut stream).unwrap();
+
+        let mut frame = Vec::<u8>::new();
+        let delivery = write_message(&mut frame, &payload, true).unwrap();
+        assert_eq!(delivery.bytes, frame.len());
+
+        assert!(stream.len() + 1 >= 126);
+        assert_eq!(&frame[..4], &[0xc1, 126, 0, (stream.len() + 1) as u8]);
+        assert_eq!(&frame[4..frame.len() - 1], stream.as_slice());
+        assert_eq!(frame[frame.len() - 1], 0x00);
+    }
+
+    #[test]
+    fn test_too_large() {
+        let mut payload = deflate_raw(Payload::new(Box::new([
+            Segment::Bomb(Bomb::new(Box::new([0x61]))),
+        ])));
+        payload.fill(&(BigUint::from(1u64 << 63) - 1u8));
+        assert!(payload.size() + 1u8 >= BigUint::from(1u64 << 63));
+
+        let mut frame = Vec::<u8>::new();
+        match write_message(&mut frame, &payload, false) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("Sent a message longer than 2^63 - 1 bytes"),
+        }
+        assert!(frame.is_empty());
+    }
+}